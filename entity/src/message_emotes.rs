@@ -0,0 +1,43 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// An emote used in a message. The channel and time are copied from the
+/// message, so emotes can be counted without looking at messages.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "message_emotes")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false, column_name = "message-id")]
+	pub message_id: Uuid,
+	#[sea_orm(
+		primary_key,
+		auto_increment = false,
+		column_name = "emote-id",
+		column_type = "Text"
+	)]
+	pub emote_id: String,
+	#[sea_orm(column_type = "Text")]
+	pub channel: String,
+	pub timestamp: TimeDateTime,
+	/// The emote's text in the message
+	#[sea_orm(column_type = "Text")]
+	pub name: String,
+	/// How many times the emote is in the message
+	pub count: i32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {}
+
+impl RelationTrait for Relation {
+	fn def(&self) -> RelationDef {
+		panic!("No RelationDef")
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -5,8 +5,9 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "messages")]
 pub struct Model {
 	#[sea_orm(primary_key, auto_increment = false)]
@@ -36,6 +37,12 @@ pub struct Model {
 	pub badges: Option<String>,
 	#[sea_orm(column_name = "user-type", column_type = "Text", nullable)]
 	pub user_type: Option<String>,
+	#[sea_orm(column_name = "badge-info", column_type = "Text", nullable)]
+	pub badge_info: Option<String>,
+	#[sea_orm(column_name = "sub-months", nullable)]
+	pub sub_months: Option<i32>,
+	#[sea_orm(column_name = "bits-tier", nullable)]
+	pub bits_tier: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
@@ -4,4 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-pub use super::messages::Entity as Messages;
+pub use super::{
+	message_emotes::Entity as MessageEmotes, messages::Entity as Messages,
+	raw_lines::Entity as RawLines, streams::Entity as Streams,
+};
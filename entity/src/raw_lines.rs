@@ -0,0 +1,34 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "raw_lines")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i64,
+	#[sea_orm(column_name = "received-at")]
+	pub received_at: TimeDateTime,
+	#[sea_orm(column_type = "Text", nullable)]
+	pub channel: Option<String>,
+	#[sea_orm(column_type = "Text")]
+	pub command: String,
+	#[sea_orm(column_type = "Text")]
+	pub line: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {}
+
+impl RelationTrait for Relation {
+	fn def(&self) -> RelationDef {
+		panic!("No RelationDef")
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,38 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "streams")]
+pub struct Model {
+	/// Twitch's ID for the stream
+	#[sea_orm(primary_key, auto_increment = false, column_type = "Text")]
+	pub id: String,
+	#[sea_orm(column_type = "Text")]
+	pub channel: String,
+	#[sea_orm(column_name = "started-at")]
+	pub started_at: TimeDateTime,
+	/// When the stream was seen going offline, if it has
+	#[sea_orm(column_name = "ended-at")]
+	pub ended_at: Option<TimeDateTime>,
+	/// When the stream was last seen live, which is as late as it could have
+	/// ended if the logger wasn't running to see it go offline
+	#[sea_orm(column_name = "last-seen-at")]
+	pub last_seen_at: Option<TimeDateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {}
+
+impl RelationTrait for Relation {
+	fn def(&self) -> RelationDef {
+		panic!("No RelationDef")
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -6,12 +6,28 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220904_144829_create_messages;
+mod m20221020_174511_create_raw_lines;
+mod m20221021_153012_create_streams;
+mod m20221106_093000_index_messages;
+mod m20221107_101500_signed_ids;
+mod m20221108_140000_create_message_emotes;
+mod m20221109_160000_parse_badges;
+mod m20221110_120000_stream_last_seen;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
 	fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-		vec![Box::new(m20220904_144829_create_messages::Migration)]
+		vec![
+			Box::new(m20220904_144829_create_messages::Migration),
+			Box::new(m20221020_174511_create_raw_lines::Migration),
+			Box::new(m20221021_153012_create_streams::Migration),
+			Box::new(m20221106_093000_index_messages::Migration),
+			Box::new(m20221107_101500_signed_ids::Migration),
+			Box::new(m20221108_140000_create_message_emotes::Migration),
+			Box::new(m20221109_160000_parse_badges::Migration),
+			Box::new(m20221110_120000_stream_last_seen::Migration),
+		]
 	}
 }
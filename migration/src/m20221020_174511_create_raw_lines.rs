@@ -0,0 +1,63 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(RawLines::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(RawLines::Id)
+							.big_integer()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(RawLines::ReceivedAt).timestamp().not_null())
+					.col(ColumnDef::new(RawLines::Channel).text())
+					.col(ColumnDef::new(RawLines::Command).text().not_null())
+					.col(ColumnDef::new(RawLines::Line).text().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-raw-lines-channel-received-at")
+					.table(RawLines::Table)
+					.col(RawLines::Channel)
+					.col(RawLines::ReceivedAt)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(RawLines::Table).to_owned())
+			.await
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RawLines {
+	#[iden = "raw_lines"]
+	Table,
+	Id,
+	#[iden = "received-at"]
+	ReceivedAt,
+	Channel,
+	Command,
+	Line,
+}
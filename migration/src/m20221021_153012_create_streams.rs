@@ -0,0 +1,55 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Streams::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(Streams::Id).text().not_null().primary_key())
+					.col(ColumnDef::new(Streams::Channel).text().not_null())
+					.col(ColumnDef::new(Streams::StartedAt).timestamp().not_null())
+					.col(ColumnDef::new(Streams::EndedAt).timestamp())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-streams-channel-started-at")
+					.table(Streams::Table)
+					.col(Streams::Channel)
+					.col(Streams::StartedAt)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(Streams::Table).to_owned())
+			.await
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Streams {
+	Table,
+	Id,
+	Channel,
+	#[iden = "started-at"]
+	StartedAt,
+	#[iden = "ended-at"]
+	EndedAt,
+}
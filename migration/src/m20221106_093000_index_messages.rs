@@ -0,0 +1,84 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-messages-channel-timestamp")
+					.table(Messages::Table)
+					.col(Messages::Channel)
+					.col(Messages::Timestamp)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-messages-room-id-user-id-timestamp")
+					.table(Messages::Table)
+					.col(Messages::RoomId)
+					.col(Messages::UserId)
+					.col(Messages::Timestamp)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-messages-username")
+					.table(Messages::Table)
+					.col(Messages::Username)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-messages-replying-to")
+					.table(Messages::Table)
+					.col(Messages::ReplyingTo)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for name in [
+			"idx-messages-channel-timestamp",
+			"idx-messages-room-id-user-id-timestamp",
+			"idx-messages-username",
+			"idx-messages-replying-to",
+		] {
+			manager
+				.drop_index(Index::drop().name(name).table(Messages::Table).to_owned())
+				.await?;
+		}
+		Ok(())
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Messages {
+	#[iden = "messages"]
+	Table,
+	Channel,
+	#[iden = "room-id"]
+	RoomId,
+	#[iden = "user-id"]
+	UserId,
+	Username,
+	Timestamp,
+	#[iden = "replying-to"]
+	ReplyingTo,
+}
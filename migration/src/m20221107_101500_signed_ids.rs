@@ -0,0 +1,53 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::{prelude::*, sea_orm::DbBackend};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Changes the type of the room and user IDs. SQLite can't change a column's
+/// type, but its integers are always signed 64-bit, so it doesn't need to.
+async fn set_id_type(
+	manager: &SchemaManager<'_>,
+	column_type: fn(&mut ColumnDef) -> &mut ColumnDef,
+) -> Result<(), DbErr> {
+	if manager.get_database_backend() == DbBackend::Sqlite {
+		return Ok(());
+	}
+	manager
+		.alter_table(
+			Table::alter()
+				.table(Messages::Table)
+				.modify_column(column_type(&mut ColumnDef::new(Messages::RoomId)).not_null())
+				.modify_column(column_type(&mut ColumnDef::new(Messages::UserId)).not_null())
+				.to_owned(),
+		)
+		.await
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// They were unsigned, which only MySQL has, while they're read and
+		// written as signed everywhere else
+		set_id_type(manager, ColumnDef::big_integer).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		set_id_type(manager, ColumnDef::big_unsigned).await
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Messages {
+	#[iden = "messages"]
+	Table,
+	#[iden = "room-id"]
+	RoomId,
+	#[iden = "user-id"]
+	UserId,
+}
@@ -0,0 +1,69 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(MessageEmotes::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(MessageEmotes::MessageId).uuid().not_null())
+					.col(ColumnDef::new(MessageEmotes::EmoteId).text().not_null())
+					.col(ColumnDef::new(MessageEmotes::Channel).text().not_null())
+					.col(
+						ColumnDef::new(MessageEmotes::Timestamp)
+							.timestamp()
+							.not_null(),
+					)
+					.col(ColumnDef::new(MessageEmotes::Name).text().not_null())
+					.col(ColumnDef::new(MessageEmotes::Count).integer().not_null())
+					.primary_key(
+						Index::create()
+							.col(MessageEmotes::MessageId)
+							.col(MessageEmotes::EmoteId),
+					)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx-message-emotes-channel-timestamp")
+					.table(MessageEmotes::Table)
+					.col(MessageEmotes::Channel)
+					.col(MessageEmotes::Timestamp)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(MessageEmotes::Table).to_owned())
+			.await
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum MessageEmotes {
+	#[iden = "message_emotes"]
+	Table,
+	#[iden = "message-id"]
+	MessageId,
+	#[iden = "emote-id"]
+	EmoteId,
+	Channel,
+	Timestamp,
+	Name,
+	Count,
+}
@@ -0,0 +1,161 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::{
+	prelude::*,
+	sea_orm::{prelude::Uuid, ConnectionTrait, DbBackend, Statement},
+};
+use std::collections::BTreeMap;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// How many messages to backfill at one time
+const MESSAGES_PER_BATCH: u64 = 10_000;
+
+/// The roles and bits tier in a `badges` tag like `subscriber/24,bits/1000`.
+/// This mirrors `Badges::parse` in the logger, which migrations can't depend
+/// on, and which this migration shouldn't follow if it changes later.
+fn parse_badges(badges: &str) -> (bool, bool, bool, Option<i32>) {
+	let badges = badges
+		.split(',')
+		.filter_map(|badge| badge.split_once('/'))
+		.collect::<Vec<_>>();
+	let has = |names: &[&str]| badges.iter().any(|(name, _)| names.contains(name));
+	let bits_tier = badges
+		.iter()
+		.find(|(name, _)| *name == "bits")
+		.and_then(|(_, version)| version.parse().ok());
+	(
+		has(&["subscriber", "founder"]),
+		has(&["moderator"]),
+		has(&["vip"]),
+		bits_tier,
+	)
+}
+
+/// Sets the roles and bits tier of every message from its badges, as the roles
+/// used to be set whenever their tags were sent at all, even as `0`. The
+/// months subscribed can't be filled in, as `badge-info` wasn't kept.
+async fn backfill(manager: &SchemaManager<'_>) -> Result<(), DbErr> {
+	let db = manager.get_connection();
+	let backend = manager.get_database_backend();
+	let mut last_id = None::<Uuid>;
+	loop {
+		let mut select = Query::select();
+		select
+			.columns([Messages::Id, Messages::Badges])
+			.from(Messages::Table)
+			.order_by(Messages::Id, Order::Asc)
+			.limit(MESSAGES_PER_BATCH);
+		if let Some(last_id) = last_id {
+			select.and_where(Expr::col(Messages::Id).gt(last_id));
+		}
+		let rows = db.query_all(backend.build(&select)).await?;
+		if rows.is_empty() {
+			return Ok(());
+		}
+		// Most messages share a few combinations of roles, so each batch only
+		// takes a few updates
+		let mut batch = BTreeMap::<_, Vec<Uuid>>::new();
+		for row in rows {
+			let id: Uuid = row.try_get("", "id")?;
+			let badges: Option<String> = row.try_get("", "badges")?;
+			batch
+				.entry(parse_badges(badges.as_deref().unwrap_or("")))
+				.or_default()
+				.push(id);
+			last_id = Some(id);
+		}
+		for ((subscriber, moderator, vip, bits_tier), ids) in batch {
+			db.execute(
+				backend.build(
+					Query::update()
+						.table(Messages::Table)
+						.values([
+							(Messages::Subscriber, subscriber.into()),
+							(Messages::Moderator, moderator.into()),
+							(Messages::Vip, vip.into()),
+							(Messages::BitsTier, bits_tier.into()),
+						])
+						.and_where(Expr::col(Messages::Id).is_in(ids)),
+				),
+			)
+			.await?;
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// SQLite can only add one column at a time
+		for mut column in [
+			ColumnDef::new(Messages::BadgeInfo).text().null().to_owned(),
+			ColumnDef::new(Messages::SubMonths)
+				.integer()
+				.null()
+				.to_owned(),
+			ColumnDef::new(Messages::BitsTier)
+				.integer()
+				.null()
+				.to_owned(),
+		] {
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Messages::Table)
+						.add_column(&mut column)
+						.to_owned(),
+				)
+				.await?;
+		}
+		backfill(manager).await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		for column in ["badge-info", "sub-months", "bits-tier"] {
+			// sea-query can't drop columns from SQLite tables, though SQLite
+			// itself can
+			if manager.get_database_backend() == DbBackend::Sqlite {
+				manager
+					.get_connection()
+					.execute(Statement::from_string(
+						DbBackend::Sqlite,
+						format!(r#"ALTER TABLE messages DROP COLUMN "{}""#, column),
+					))
+					.await?;
+				continue;
+			}
+			manager
+				.alter_table(
+					Table::alter()
+						.table(Messages::Table)
+						.drop_column(Alias::new(column))
+						.to_owned(),
+				)
+				.await?;
+		}
+		Ok(())
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Messages {
+	#[iden = "messages"]
+	Table,
+	Id,
+	Subscriber,
+	Moderator,
+	Vip,
+	Badges,
+	#[iden = "badge-info"]
+	BadgeInfo,
+	#[iden = "sub-months"]
+	SubMonths,
+	#[iden = "bits-tier"]
+	BitsTier,
+}
@@ -0,0 +1,57 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use sea_orm_migration::{
+	prelude::*,
+	sea_orm::{ConnectionTrait, DbBackend, Statement},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Streams::Table)
+					.add_column(ColumnDef::new(Streams::LastSeenAt).timestamp().null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// sea-query can't drop columns from SQLite tables, though SQLite itself
+		// can
+		if manager.get_database_backend() == DbBackend::Sqlite {
+			manager
+				.get_connection()
+				.execute(Statement::from_string(
+					DbBackend::Sqlite,
+					r#"ALTER TABLE streams DROP COLUMN "last-seen-at""#.to_string(),
+				))
+				.await?;
+			return Ok(());
+		}
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Streams::Table)
+					.drop_column(Streams::LastSeenAt)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Streams {
+	Table,
+	#[iden = "last-seen-at"]
+	LastSeenAt,
+}
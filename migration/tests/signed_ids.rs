@@ -0,0 +1,188 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Runs the migration that makes room and user IDs signed up and down, with
+//! messages from before and after it. Postgres and MySQL are only tested with
+//! `--ignored`, with `TEST_POSTGRES_URL` and `TEST_MYSQL_URL` set, and
+//! everything in their databases is dropped.
+
+use migration::{Migrator, MigratorTrait};
+use sea_orm_migration::{
+	prelude::*,
+	sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbBackend, Statement},
+};
+
+/// Room and user IDs that need more than 32 bits, sorted by room ID
+const IDS: [(i64, i64); 2] = [(23_936_415, 4_294_967_296), (i64::MAX, 1)];
+/// Room and user IDs that only fit once they're signed
+const NEGATIVE_IDS: (i64, i64) = (-1, i64::MIN);
+
+async fn insert(
+	db: &DatabaseConnection,
+	number: u32,
+	(room_id, user_id): (i64, i64),
+) -> Result<(), DbErr> {
+	let query = Query::insert()
+		.into_table(Alias::new("messages"))
+		.columns(
+			[
+				"id",
+				"channel",
+				"room-id",
+				"user-id",
+				"username",
+				"message",
+				"timestamp",
+			]
+			.map(Alias::new),
+		)
+		.exprs_panic([
+			// A literal, which Postgres takes as a UUID
+			Expr::cust(&format!("'00000000-0000-0000-0000-{:012}'", number)),
+			Expr::val("jerma985").into(),
+			Expr::val(room_id).into(),
+			Expr::val(user_id).into(),
+			Expr::val("alice").into(),
+			Expr::val("hi").into(),
+			Expr::cust("CURRENT_TIMESTAMP"),
+		])
+		.to_owned();
+	db.execute(db.get_database_backend().build(&query))
+		.await
+		.map(|_| ())
+}
+
+async fn ids(db: &DatabaseConnection) -> Result<Vec<(i64, i64)>, DbErr> {
+	let query = Query::select()
+		.columns([Alias::new("room-id"), Alias::new("user-id")])
+		.from(Alias::new("messages"))
+		.order_by(Alias::new("room-id"), Order::Asc)
+		.to_owned();
+	db.query_all(db.get_database_backend().build(&query))
+		.await?
+		.iter()
+		.map(|row| Ok((row.try_get("", "room-id")?, row.try_get("", "user-id")?)))
+		.collect()
+}
+
+/// Gets the types of the room and user ID columns, without MySQL's display
+/// width
+async fn id_types(db: &DatabaseConnection) -> Result<Vec<String>, DbErr> {
+	let backend = db.get_database_backend();
+	let sql = match backend {
+		DbBackend::Sqlite => {
+			r#"SELECT type FROM pragma_table_info('messages')
+			WHERE name IN ('room-id', 'user-id') ORDER BY name"#
+		}
+		DbBackend::Postgres => {
+			r#"SELECT data_type AS type FROM information_schema.columns
+			WHERE table_schema = current_schema() AND table_name = 'messages'
+			AND column_name IN ('room-id', 'user-id') ORDER BY column_name"#
+		}
+		DbBackend::MySql => {
+			r#"SELECT CAST(column_type AS CHAR) AS type FROM information_schema.columns
+			WHERE table_schema = DATABASE() AND table_name = 'messages'
+			AND column_name IN ('room-id', 'user-id') ORDER BY column_name"#
+		}
+	};
+	db.query_all(Statement::from_string(backend, sql.to_string()))
+		.await?
+		.iter()
+		.map(|row| {
+			let column_type: String = row.try_get("", "type")?;
+			Ok(column_type.to_lowercase().replace("(20)", ""))
+		})
+		.collect()
+}
+
+/// Runs the migration, checking that the ID columns have the `unsigned` type
+/// before it and the `signed` type after it, and that no ID changes.
+async fn check_signed_ids(
+	db: &DatabaseConnection,
+	unsigned: &str,
+	signed: &str,
+) -> Result<(), DbErr> {
+	let before = Migrator::migrations()
+		.iter()
+		.position(|migration| migration.name() == "m20221107_101500_signed_ids")
+		.expect("no signed_ids migration") as u32;
+	Migrator::up(db, Some(before)).await?;
+	assert_eq!(id_types(db).await?, [unsigned; 2]);
+	insert(db, 0, IDS[0]).await?;
+	Migrator::up(db, Some(1)).await?;
+	assert_eq!(id_types(db).await?, [signed; 2]);
+	insert(db, 1, IDS[1]).await?;
+	assert_eq!(ids(db).await?, IDS);
+
+	insert(db, 2, NEGATIVE_IDS).await?;
+	assert_eq!(ids(db).await?, [NEGATIVE_IDS, IDS[0], IDS[1]]);
+	// Unsigned columns can't hold them again
+	let delete = Query::delete()
+		.from_table(Alias::new("messages"))
+		.and_where(Expr::col(Alias::new("room-id")).lt(0))
+		.to_owned();
+	db.execute(db.get_database_backend().build(&delete)).await?;
+
+	Migrator::down(db, Some(1)).await?;
+	assert_eq!(id_types(db).await?, [unsigned; 2]);
+	assert_eq!(ids(db).await?, IDS);
+	Migrator::up(db, Some(1)).await?;
+	assert_eq!(id_types(db).await?, [signed; 2]);
+	assert_eq!(ids(db).await?, IDS);
+	Ok(())
+}
+
+#[async_std::test]
+async fn signed_ids_sqlite() -> Result<(), DbErr> {
+	let path = std::env::temp_dir().join(format!("signed-ids-{}.db", std::process::id()));
+	let db = Database::connect(format!("sqlite://{}?mode=rwc", path.display())).await?;
+	// SQLite's integers are always signed 64-bit
+	let result = check_signed_ids(&db, "integer", "integer").await;
+	let _ = std::fs::remove_file(&path);
+	result
+}
+
+#[async_std::test]
+#[ignore = "needs TEST_POSTGRES_URL"]
+async fn signed_ids_postgres() -> Result<(), DbErr> {
+	let db =
+		Database::connect(std::env::var("TEST_POSTGRES_URL").expect("TEST_POSTGRES_URL isn't set"))
+			.await?;
+	for sql in ["DROP SCHEMA public CASCADE", "CREATE SCHEMA public"] {
+		db.execute(Statement::from_string(
+			db.get_database_backend(),
+			sql.to_string(),
+		))
+		.await?;
+	}
+	// Postgres has no unsigned integers, so they were already signed
+	check_signed_ids(&db, "bigint", "bigint").await
+}
+
+#[async_std::test]
+#[ignore = "needs TEST_MYSQL_URL"]
+async fn signed_ids_mysql() -> Result<(), DbErr> {
+	let url = std::env::var("TEST_MYSQL_URL").expect("TEST_MYSQL_URL isn't set");
+	let db = Database::connect(&url).await?;
+	let name: String = db
+		.query_one(Statement::from_string(
+			DbBackend::MySql,
+			"SELECT DATABASE() AS name".to_string(),
+		))
+		.await?
+		.expect("no database selected")
+		.try_get("", "name")?;
+	for sql in [
+		format!("DROP DATABASE `{}`", name),
+		format!("CREATE DATABASE `{}`", name),
+	] {
+		db.execute(Statement::from_string(DbBackend::MySql, sql))
+			.await?;
+	}
+	// Connections from before the database was dropped don't use the new one
+	let db = Database::connect(&url).await?;
+	check_signed_ids(&db, "bigint unsigned", "bigint").await
+}
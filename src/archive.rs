@@ -0,0 +1,325 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reads messages back out of rollups, for days that retention has pruned from
+//! the database, so searching them still works.
+
+use crate::{
+	config::{Config, RollupFormat},
+	import::{open_lines, TextRollupReader},
+	manifest::{Manifest, ManifestEntry},
+	rollup::{self, RollupPeriod, MAX_MESSAGES_PER_PAGE},
+	server, timezone,
+};
+use async_stream::try_stream;
+use color_eyre::eyre::{Result, WrapErr};
+use entity::messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message};
+use futures_util::Stream;
+use sea_orm::{prelude::*, DatabaseConnection, QueryOrder};
+use std::{collections::BTreeSet, path::Path};
+use time::{Date, Duration, PrimitiveDateTime};
+use time_tz::Tz;
+
+/// A part of a search, which is either read from the database or from rollups
+enum SearchSpan {
+	Database {
+		start: Option<PrimitiveDateTime>,
+		end: Option<PrimitiveDateTime>,
+	},
+	/// A local day that's been pruned from the database
+	Archived {
+		date: Date,
+		start: PrimitiveDateTime,
+		end: PrimitiveDateTime,
+	},
+}
+
+/// Checks whether a local day's messages are still in the database
+async fn has_messages(
+	db: &DatabaseConnection,
+	channel: &str,
+	date: Date,
+	zone: &Tz,
+) -> Result<bool> {
+	Ok(MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(channel))
+		.filter(MessageColumn::Timestamp.between(
+			timezone::start_of_day(date, zone),
+			rollup::end_of_day(date, zone),
+		))
+		.count(db)
+		.await
+		.wrap_err("failed to count messages")?
+		> 0)
+}
+
+/// Splits a search of a channel into the days that only rollups have, and the
+/// spans of time between them that are still in the database. Days are pruned
+/// oldest first, so every day from the first rollup up to the oldest message
+/// left is archived. After that, only days that were rolled up are checked, as
+/// days that couldn't be pruned can leave gaps.
+async fn search_spans(
+	db: &DatabaseConnection,
+	config: &Config,
+	manifest: &Manifest,
+	channel: &str,
+	start: Option<PrimitiveDateTime>,
+	end: Option<PrimitiveDateTime>,
+) -> Result<Vec<SearchSpan>> {
+	let retention = config.channel_retention(channel);
+	let rolled_up = manifest
+		.rollups
+		.values()
+		.filter(|entry| entry.channel == channel && entry.period != RollupPeriod::Month)
+		.map(|entry| entry.date)
+		.collect::<BTreeSet<_>>();
+	let (retention, first_rolled_up) = match (retention, rolled_up.first()) {
+		(Some(retention), Some(first_rolled_up)) => (retention, *first_rolled_up),
+		_ => return Ok(vec![SearchSpan::Database { start, end }]),
+	};
+	let zone = config.channel_timezone(channel);
+	let cutoff = timezone::today(zone) - Duration::days(i64::from(retention));
+	let oldest = MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(channel))
+		.order_by_asc(MessageColumn::Timestamp)
+		.one(db)
+		.await
+		.wrap_err("failed to get oldest message")?
+		.map(|oldest| timezone::to_local(oldest.timestamp, zone).date());
+	let last = end.map(|end| timezone::to_local(end, zone).date());
+	let mut date = match start {
+		Some(start) => first_rolled_up.max(timezone::to_local(start, zone).date()),
+		None => first_rolled_up,
+	};
+	let mut spans = Vec::new();
+	let mut db_start = start;
+	while date < cutoff && last.is_none_or(|last| date <= last) {
+		let archived = if oldest.is_none_or(|oldest| date < oldest) {
+			true
+		} else {
+			rolled_up.contains(&date) && !has_messages(db, channel, date, zone).await?
+		};
+		if archived {
+			let (day_start, day_end) = (
+				timezone::start_of_day(date, zone),
+				rollup::end_of_day(date, zone),
+			);
+			if db_start.is_none_or(|db_start| db_start < day_start) {
+				spans.push(SearchSpan::Database {
+					start: db_start,
+					end: Some(day_start - Duration::nanoseconds(1)),
+				});
+			}
+			spans.push(SearchSpan::Archived {
+				date,
+				start: start.map_or(day_start, |start| start.max(day_start)),
+				end: end.map_or(day_end, |end| end.min(day_end)),
+			});
+			db_start = Some(day_end + Duration::nanoseconds(1));
+		}
+		date = date.next_day().expect("ran out of days");
+	}
+	spans.push(SearchSpan::Database {
+		start: db_start,
+		end,
+	});
+	Ok(spans)
+}
+
+/// Reads the messages from a JSONL rollup that pass the filter
+async fn read_jsonl(
+	path: &Path,
+	keep: impl Fn(&Message) -> bool,
+	messages: &mut Vec<Message>,
+) -> Result<()> {
+	let mut lines = open_lines(path)
+		.await
+		.wrap_err_with(|| format!("failed to open {}", path.display()))?;
+	while let Some(line) = lines
+		.next_line()
+		.await
+		.wrap_err_with(|| format!("failed to read {}", path.display()))?
+	{
+		if line.trim().is_empty() {
+			continue;
+		}
+		let message = serde_json::from_str::<Message>(&line)
+			.wrap_err_with(|| format!("failed to parse message in {}", path.display()))?;
+		if keep(&message) {
+			messages.push(message);
+		}
+	}
+	Ok(())
+}
+
+/// Reads the messages from a text rollup that pass the filter. Text rollups
+/// only have the time, username and message, so the rest is left empty.
+async fn read_text(
+	config: &Config,
+	path: &Path,
+	keep: impl Fn(&Message) -> bool,
+	messages: &mut Vec<Message>,
+) -> Result<()> {
+	let mut reader = TextRollupReader::open(config, path)
+		.await
+		.wrap_err_with(|| format!("failed to open {}", path.display()))?;
+	while let Some((id, message)) = reader.next_message().await? {
+		let message = Message {
+			id,
+			channel: reader.channel.clone(),
+			room_id: 0,
+			user_id: 0,
+			username: message.username,
+			message: message.message,
+			timestamp: message.timestamp,
+			deleted: message.deleted_at.is_some(),
+			deleted_at: message.deleted_at,
+			replying_to: None,
+			subscriber: false,
+			moderator: false,
+			vip: false,
+			emotes: None,
+			badges: None,
+			user_type: None,
+			badge_info: None,
+			sub_months: None,
+			bits_tier: None,
+		};
+		if keep(&message) {
+			messages.push(message);
+		}
+	}
+	Ok(())
+}
+
+/// Reads a channel's messages on a pruned day out of its rollups, including
+/// the rollups of streams that overlap with it. JSONL rollups are preferred,
+/// as they have every column, then text rollups.
+async fn archived_messages(
+	db: &DatabaseConnection,
+	config: &Config,
+	manifest: &Manifest,
+	channel: &str,
+	date: Date,
+	start: PrimitiveDateTime,
+	end: PrimitiveDateTime,
+) -> Result<Vec<Message>> {
+	let zone = config.channel_timezone(channel);
+	let streams = rollup::streams_between(
+		db,
+		config,
+		timezone::start_of_day(date, zone),
+		rollup::end_of_day(date, zone),
+	)
+	.await?
+	.into_iter()
+	.filter(|stream| stream.channel == channel)
+	.map(|stream| stream.id)
+	.collect::<Vec<_>>();
+	let in_day = |entry: &ManifestEntry| {
+		entry.channel == channel
+			&& match &entry.period {
+				RollupPeriod::Stream(id) => streams.contains(id),
+				// Only Parquet is rolled up by month, which isn't read back
+				RollupPeriod::Month => false,
+				_ => entry.date == date,
+			}
+	};
+	let mut periods = Vec::<&RollupPeriod>::new();
+	for entry in manifest.rollups.values().filter(|entry| in_day(entry)) {
+		if !periods.contains(&&entry.period) {
+			periods.push(&entry.period);
+		}
+	}
+
+	let keep = |message: &Message| message.timestamp >= start && message.timestamp <= end;
+	let mut messages = Vec::new();
+	for period in periods {
+		let readable = manifest
+			.rollups
+			.iter()
+			.filter(|(path, entry)| {
+				in_day(entry)
+					&& entry.period == *period
+					&& matches!(entry.format, RollupFormat::Jsonl | RollupFormat::Text)
+					&& config.rollup_dir.join(path).exists()
+			})
+			.min_by_key(|(_, entry)| entry.format != RollupFormat::Jsonl);
+		match readable {
+			Some((path, entry)) if entry.format == RollupFormat::Jsonl => {
+				read_jsonl(&config.rollup_dir.join(path), keep, &mut messages).await?
+			}
+			Some((path, _)) => {
+				read_text(config, &config.rollup_dir.join(path), keep, &mut messages).await?
+			}
+			None => warn!(
+				"#{} on {} ({:?}) has no JSONL or text rollup on disk to search",
+				channel, date, period
+			),
+		}
+	}
+	messages.sort_by_key(|message| message.timestamp);
+	Ok(messages)
+}
+
+/// Searches a channel's messages, oldest first, reading days that retention
+/// has pruned out of their rollups and everything else from the database.
+pub fn search<'a>(
+	db: &'a DatabaseConnection,
+	config: &'a Config,
+	channel: &'a str,
+	users: &'a [String],
+	start: Option<PrimitiveDateTime>,
+	end: Option<PrimitiveDateTime>,
+	limit: u64,
+) -> impl Stream<Item = Result<Message>> + 'a {
+	try_stream! {
+		let channel = channel.to_lowercase();
+		let manifest = match config.channel_retention(&channel) {
+			Some(_) => Manifest::read(config).await?,
+			None => Manifest::default(),
+		};
+		let mut read = 0_u64;
+		'spans: for span in search_spans(db, config, &manifest, &channel, start, end).await? {
+			match span {
+				SearchSpan::Database { start, end } => {
+					let mut message_pages = server::search_query(&channel, users, start, end)
+						.order_by_asc(MessageColumn::Timestamp)
+						.paginate(db, MAX_MESSAGES_PER_PAGE);
+					while let Some(messages) = message_pages
+						.fetch_and_next()
+						.await
+						.wrap_err("failed to get messages")?
+					{
+						for message in messages {
+							if read >= limit {
+								break 'spans;
+							}
+							read += 1;
+							yield message;
+						}
+					}
+				}
+				SearchSpan::Archived { date, start, end } => {
+					let messages =
+						archived_messages(db, config, &manifest, &channel, date, start, end).await?;
+					for message in messages.into_iter().filter(|message| {
+						users.is_empty()
+							|| users
+								.iter()
+								.any(|user| user.eq_ignore_ascii_case(&message.username))
+					}) {
+						if read >= limit {
+							break 'spans;
+						}
+						read += 1;
+						yield message;
+					}
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,116 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parses Twitch's `badges` and `badge-info` tags, like
+//! `subscriber/24,bits/1000`, and the role tags sent alongside them.
+
+use std::collections::HashMap;
+
+/// What a message's badges say about who sent it
+#[derive(Debug, Default)]
+pub struct Badges {
+	pub subscriber: bool,
+	pub moderator: bool,
+	pub vip: bool,
+	/// How many months they've been subscribed, from `badge-info`
+	pub sub_months: Option<i32>,
+	/// The bits badge they have, like 1000
+	pub bits_tier: Option<i32>,
+}
+
+/// Splits a badges tag into the name and version of each badge
+fn parse_badge_tag(tag: &str) -> Vec<(&str, &str)> {
+	tag.split(',')
+		.filter_map(|badge| badge.split_once('/'))
+		.collect()
+}
+
+/// Gets the version of the first of the badges that's in a tag, as a number
+fn badge_number(badges: &[(&str, &str)], names: &[&str]) -> Option<i32> {
+	badges
+		.iter()
+		.find(|(name, _)| names.contains(name))
+		.and_then(|(_, version)| version.parse().ok())
+}
+
+impl Badges {
+	/// Parses the badges tag and, if there is one, the badge info tag
+	pub fn parse(badges: Option<&str>, badge_info: Option<&str>) -> Self {
+		let badges = parse_badge_tag(badges.unwrap_or_default());
+		let badge_info = parse_badge_tag(badge_info.unwrap_or_default());
+		let has = |names: &[&str]| badges.iter().any(|(name, _)| names.contains(name));
+		Self {
+			subscriber: has(&["subscriber", "founder"]),
+			moderator: has(&["moderator"]),
+			vip: has(&["vip"]),
+			sub_months: badge_number(&badge_info, &["subscriber", "founder"]),
+			bits_tier: badge_number(&badges, &["bits"]),
+		}
+	}
+
+	/// Parses the badges of a chat message. Its `subscriber`, `mod` and `vip`
+	/// tags are used for the roles when they're sent, where only `1` means yes.
+	pub fn from_tags(tags: &HashMap<String, Option<String>>) -> Self {
+		let tag = |key: &str| tags.get(key).cloned().flatten();
+		let flag = |key: &str| tags.get(key).map(|value| value.as_deref() == Some("1"));
+		let badges = Self::parse(tag("badges").as_deref(), tag("badge-info").as_deref());
+		Self {
+			subscriber: flag("subscriber").unwrap_or(badges.subscriber),
+			moderator: flag("mod").unwrap_or(badges.moderator),
+			vip: flag("vip").unwrap_or(badges.vip),
+			..badges
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tags(tags: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+		tags.iter()
+			.map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+			.collect()
+	}
+
+	#[test]
+	fn parses_badges() {
+		let badges = Badges::parse(
+			Some("vip/1,subscriber/3024,bits/1000"),
+			Some("subscriber/28"),
+		);
+		assert!(badges.subscriber && badges.vip && !badges.moderator);
+		assert_eq!(badges.sub_months, Some(28));
+		assert_eq!(badges.bits_tier, Some(1000));
+		let founder = Badges::parse(Some("founder/0"), Some("founder/3"));
+		assert!(founder.subscriber);
+		assert_eq!(founder.sub_months, Some(3));
+		let none = Badges::parse(None, None);
+		assert!(!none.subscriber && !none.moderator && !none.vip);
+		assert_eq!((none.sub_months, none.bits_tier), (None, None));
+	}
+
+	#[test]
+	fn role_tags_override_badges() {
+		let badges = "moderator/1,subscriber/12";
+		let with_mod = |value| tags(&[("badges", Some(badges)), ("mod", value)]);
+		assert!(!Badges::from_tags(&with_mod(Some("0"))).moderator);
+		assert!(Badges::from_tags(&with_mod(Some("1"))).moderator);
+		assert!(!Badges::from_tags(&with_mod(Some(""))).moderator);
+		assert!(!Badges::from_tags(&with_mod(None)).moderator);
+		let subscriber = tags(&[("subscriber", Some("0")), ("mod", Some("1"))]);
+		let subscriber = Badges::from_tags(&subscriber);
+		assert!(!subscriber.subscriber && subscriber.moderator);
+	}
+
+	#[test]
+	fn badges_are_used_without_role_tags() {
+		let badges = Badges::from_tags(&tags(&[("badges", Some("moderator/1,vip/1"))]));
+		assert!(badges.moderator && badges.vip && !badges.subscriber);
+		let badges = Badges::from_tags(&tags(&[]));
+		assert!(!badges.moderator && !badges.vip && !badges.subscriber);
+	}
+}
@@ -0,0 +1,179 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use time::{macros::format_description, Date, UtcOffset};
+
+#[derive(Parser)]
+#[command(author, version, about)]
+pub struct Cli {
+	/// The config file to use
+	#[arg(long, global = true, default_value = "config.ron")]
+	pub config: PathBuf,
+	/// What to do. If nothing is given, the logger is ran.
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+	/// Runs the logger
+	Run,
+	/// Manages the database's migrations
+	Migrate {
+		#[command(subcommand)]
+		command: MigrateCommand,
+	},
+	/// Rolls up days of messages into text logs
+	Rollup {
+		/// The day to roll up, as YYYY-MM-DD. Defaults to yesterday.
+		#[arg(long, value_parser = parse_date, conflicts_with_all = ["from", "to", "missing"])]
+		date: Option<Date>,
+		/// The first day of a range to roll up, as YYYY-MM-DD
+		#[arg(long, value_parser = parse_date, conflicts_with = "missing")]
+		from: Option<Date>,
+		/// The last day of a range to roll up, as YYYY-MM-DD. Defaults to
+		/// yesterday.
+		#[arg(long, value_parser = parse_date, requires = "from")]
+		to: Option<Date>,
+		/// Roll up every day that has messages but no rollup
+		#[arg(long)]
+		missing: bool,
+		/// Roll up days again even if they're already rolled up
+		#[arg(long, conflicts_with = "missing")]
+		force: bool,
+	},
+	/// Checks that every rollup in the manifest is still there and unchanged
+	VerifyRollups,
+	/// Deletes messages older than each channel's retention, once they're
+	/// rolled up
+	Prune {
+		/// Only show how many messages would be deleted
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// Searches a channel's messages, like the search API
+	Search {
+		/// The channel to search
+		channel: String,
+		/// Only show messages from these users
+		#[arg(long = "user")]
+		users: Vec<String>,
+		/// Only show messages sent at or after this time, as a Unix
+		/// timestamp, RFC 3339 or RFC 2822
+		#[arg(long)]
+		start: Option<String>,
+		/// Only show messages sent at or before this time, as a Unix
+		/// timestamp, RFC 3339 or RFC 2822
+		#[arg(long)]
+		end: Option<String>,
+		/// How to print the messages
+		#[arg(long, value_enum, default_value_t = SearchFormat::Text)]
+		format: SearchFormat,
+	},
+	/// Exports a channel's messages as raw IRC lines, which can be imported
+	/// again with `import justlog`
+	Export {
+		/// The channel to export
+		channel: String,
+		/// Only export messages sent at or after this time, as a Unix
+		/// timestamp, RFC 3339 or RFC 2822
+		#[arg(long)]
+		start: Option<String>,
+		/// Only export messages sent at or before this time, as a Unix
+		/// timestamp, RFC 3339 or RFC 2822
+		#[arg(long)]
+		end: Option<String>,
+		/// The file to write to, instead of stdout
+		#[arg(short, long)]
+		output: Option<PathBuf>,
+	},
+	/// Checks that the config file is valid, and that the database and rollup
+	/// directory it points to can be used
+	CheckConfig,
+	/// Moves messages into a table partitioned by month, on Postgres. Stop the
+	/// logger while this runs.
+	Partition {
+		/// Move messages back into a plain table instead
+		#[arg(long)]
+		undo: bool,
+	},
+	/// Feeds raw IRC lines back through message processing, to apply fixes and
+	/// new columns to history
+	Reprocess {
+		/// Files of raw IRC lines to reprocess, one per line. If none are
+		/// given, the raw line archive in the database is reprocessed.
+		files: Vec<PathBuf>,
+		/// The database to write into, instead of the configured one. It
+		/// will be migrated, and the raw line archive is copied into it.
+		#[arg(long)]
+		database: Option<String>,
+	},
+	/// Imports text rollups back into the database, such as after losing it
+	ImportRollups {
+		/// Rollup files, or directories of them, to import. If none are
+		/// given, the rollup directory is imported.
+		paths: Vec<PathBuf>,
+	},
+	/// Imports chat history from another logger's files. Messages that are
+	/// already in the database are skipped, except justlog's, which go
+	/// through the same processing as `reprocess` and refresh them instead.
+	Import {
+		/// What kind of logs are being imported
+		#[arg(value_enum)]
+		format: ImportFormat,
+		/// Files, or directories of them, to import
+		paths: Vec<PathBuf>,
+		/// The UTC offset the logs' times are in, for formats that don't say
+		#[arg(long, default_value = "+00:00", value_parser = parse_utc_offset)]
+		utc_offset: UtcOffset,
+	},
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+	/// Applies pending migrations
+	Up {
+		/// How many migrations to apply. Defaults to all of them.
+		#[arg(short = 'n', long)]
+		steps: Option<u32>,
+	},
+	/// Rolls back applied migrations
+	Down {
+		/// How many migrations to roll back
+		#[arg(short = 'n', long, default_value_t = 1)]
+		steps: u32,
+	},
+	/// Shows which migrations have been applied
+	Status,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SearchFormat {
+	/// The same text as the search API
+	Text,
+	/// One JSON object per message, per line
+	Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ImportFormat {
+	/// justlog's raw IRC line logs
+	Justlog,
+	/// Chatterino's log files
+	Chatterino,
+	/// Twitch VOD chat downloads in JSON, as made by TwitchDownloader
+	TwitchVod,
+}
+
+fn parse_date(date: &str) -> Result<Date, time::error::Parse> {
+	Date::parse(date, format_description!("[year]-[month]-[day]"))
+}
+
+fn parse_utc_offset(offset: &str) -> Result<UtcOffset, time::error::Parse> {
+	UtcOffset::parse(offset, format_description!("[offset_hour]:[offset_minute]"))
+}
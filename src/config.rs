@@ -3,17 +3,251 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
-use serde::Deserialize;
-use std::path::PathBuf;
+use crate::timezone;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use migration::{Migrator, MigratorTrait};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+use time_tz::Tz;
 
 #[derive(Deserialize)]
 pub struct Config {
 	pub twitch: TwitchConfig,
 	pub database: String,
 	pub rollup_dir: PathBuf,
+	/// What formats to write rollups in. Several can be written at once.
+	#[serde(default = "default_rollup_formats")]
+	pub rollup_formats: Vec<RollupFormat>,
+	/// How to compress rollups, if at all
+	#[serde(default)]
+	pub rollup_compression: Option<RollupCompression>,
+	/// How to partition Parquet rollups
+	#[serde(default)]
+	pub parquet_partitioning: ParquetPartitioning,
+	/// The IANA timezone each channel's rollups use for their days, like
+	/// `"America/New_York"`. Channels that aren't listed use UTC.
+	#[serde(default)]
+	pub timezones: HashMap<String, String>,
+	/// How much of each channel's chat goes in each rollup file. Channels
+	/// that aren't listed get a file for each day.
+	#[serde(default)]
+	pub rollup_granularity: HashMap<String, RollupGranularity>,
+	/// How many days of each channel's messages to keep in the database.
+	/// Older messages are deleted once they're rolled up. Channels that aren't
+	/// listed keep everything.
+	#[serde(default)]
+	pub retention: HashMap<String, u32>,
+	/// Only log how many messages retention would delete
+	#[serde(default)]
+	pub retention_dry_run: bool,
+	/// S3-compatible object storage to upload finished rollups to, if any
+	#[serde(default)]
+	pub upload: Option<UploadConfig>,
+	/// Keep messages in a partition for each month, if the database is
+	/// Postgres
+	#[serde(default)]
+	pub partitioning: Option<PartitionConfig>,
 	pub port: u16,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RollupFormat {
+	/// Plain text, with only the time, username and message
+	Text,
+	/// JSON Lines, with every column of each message
+	Jsonl,
+	/// CSV, with every column of each message
+	Csv,
+	/// Apache Parquet, with every column of each message
+	Parquet,
+}
+
+#[derive(Deserialize)]
+pub struct UploadConfig {
+	/// The endpoint of the S3 API, like `https://s3.us-east-1.amazonaws.com`,
+	/// or `http://localhost:9000` for a local MinIO
+	pub endpoint: String,
+	#[serde(default = "default_upload_region")]
+	pub region: String,
+	pub bucket: String,
+	/// Put in front of each rollup's path in the rollup directory to get its
+	/// key in the bucket
+	#[serde(default)]
+	pub prefix: String,
+	pub access_key: String,
+	pub secret_key: String,
+	/// How many times to try uploading a rollup before giving up until the
+	/// next rollup
+	#[serde(default = "default_upload_attempts")]
+	pub attempts: u32,
+	/// Whether to delete rollups from the rollup directory once they're
+	/// uploaded
+	#[serde(default)]
+	pub delete_local: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PartitionConfig {
+	/// How many months after this one to create partitions for ahead of time
+	#[serde(default = "default_months_ahead")]
+	pub months_ahead: u32,
+	/// Detach the partitions of months that ended this many months ago or
+	/// longer, leaving them as tables of their own to archive. Partitions are
+	/// never detached if this isn't set.
+	#[serde(default)]
+	pub detach_after_months: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum RollupGranularity {
+	/// A file for each day
+	#[default]
+	Day,
+	/// A file for each hour of each day
+	Hour,
+	/// A file for each stream, from when it went live until it went offline.
+	/// Chat while the channel is offline still gets a file for each day.
+	Stream,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParquetPartitioning {
+	/// A partition for each channel's day
+	#[default]
+	Day,
+	/// A partition for each channel's month, holding one file for the whole
+	/// month, written once it's over
+	Month,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct RollupCompression {
+	pub algorithm: CompressionAlgorithm,
+	/// The algorithm's compression level, or its default if not given
+	#[serde(default)]
+	pub level: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+	Gzip,
+	Zstd,
+}
+
+fn default_rollup_formats() -> Vec<RollupFormat> {
+	vec![RollupFormat::Text]
+}
+
+fn default_upload_region() -> String {
+	"us-east-1".to_string()
+}
+
+fn default_upload_attempts() -> u32 {
+	5
+}
+
+fn default_months_ahead() -> u32 {
+	3
+}
+
+impl Config {
+	/// Reads and parses a config file
+	pub async fn read(path: &Path) -> Result<Self> {
+		let config = ron::from_str::<Self>(
+			&tokio::fs::read_to_string(path)
+				.await
+				.wrap_err_with(|| format!("failed to read {}", path.display()))?,
+		)
+		.wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+		for (channel, name) in &config.timezones {
+			if timezone::by_name(name).is_none() {
+				return Err(eyre!("unknown timezone '{}' for {}", name, channel));
+			}
+		}
+		Ok(config)
+	}
+
+	/// Gets the timezone a channel's rollups use, which is UTC unless it's
+	/// configured.
+	pub fn channel_timezone(&self, channel: &str) -> &'static Tz {
+		self.timezones
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(channel))
+			.and_then(|(_, zone)| timezone::by_name(zone))
+			.unwrap_or_else(timezone::utc)
+	}
+
+	/// Gets every timezone that channels' rollups use, including UTC
+	pub fn rollup_timezones(&self) -> Vec<&'static Tz> {
+		let mut zones = vec![timezone::utc()];
+		for zone in self
+			.timezones
+			.values()
+			.filter_map(|zone| timezone::by_name(zone))
+		{
+			if !zones.iter().any(|other| timezone::same_zone(zone, other)) {
+				zones.push(zone);
+			}
+		}
+		zones
+	}
+
+	/// Gets how much of a channel's chat goes in each rollup file
+	pub fn channel_granularity(&self, channel: &str) -> RollupGranularity {
+		self.rollup_granularity
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(channel))
+			.map(|(_, granularity)| *granularity)
+			.unwrap_or_default()
+	}
+
+	/// Gets how many days of a channel's messages are kept in the database, if
+	/// they aren't kept forever
+	pub fn channel_retention(&self, channel: &str) -> Option<u32> {
+		self.retention
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case(channel))
+			.map(|(_, days)| *days)
+	}
+
+	/// Whether Parquet rollups are written a month at a time
+	pub fn parquet_by_month(&self) -> bool {
+		self.rollup_formats.contains(&RollupFormat::Parquet)
+			&& self.parquet_partitioning == ParquetPartitioning::Month
+	}
+
+	/// Gets the formats that each day, hour or stream is rolled up in, which
+	/// leaves out Parquet when it's written a month at a time
+	pub fn period_formats(&self) -> Vec<RollupFormat> {
+		self.rollup_formats
+			.iter()
+			.copied()
+			.filter(|format| !(*format == RollupFormat::Parquet && self.parquet_by_month()))
+			.collect()
+	}
+
+	/// Gets the channels that have a file for each stream, in lowercase
+	pub fn stream_channels(&self) -> Vec<String> {
+		self.rollup_granularity
+			.iter()
+			.filter(|(_, granularity)| **granularity == RollupGranularity::Stream)
+			.map(|(channel, _)| channel.to_lowercase())
+			.collect()
+	}
+
+	/// Gets the channels that are configured to use a timezone, in lowercase
+	pub fn timezone_channels(&self, zone: &Tz) -> Vec<String> {
+		self.timezones
+			.keys()
+			.filter(|channel| timezone::same_zone(self.channel_timezone(channel), zone))
+			.map(|channel| channel.to_lowercase())
+			.collect()
+	}
+}
+
 #[derive(Deserialize)]
 pub struct TwitchConfig {
 	pub username: String,
@@ -23,3 +257,41 @@ pub struct TwitchConfig {
 	pub client_secret: String,
 	pub channels: Vec<String>,
 }
+
+/// Checks that a config can be used to run the logger, printing what's wrong
+/// with it if it can't.
+pub async fn check_config(config: &Config) -> Result<()> {
+	let mut problems = Vec::new();
+	if config.twitch.channels.is_empty() {
+		problems.push("no channels to log are configured".to_string());
+	}
+	if !config.rollup_dir.is_dir() {
+		problems.push(format!(
+			"the rollup directory {} doesn't exist",
+			config.rollup_dir.display()
+		));
+	}
+	match crate::open_database(&config.database).await {
+		Ok(db) => match Migrator::get_pending_migrations(&db).await {
+			Ok(pending) if !pending.is_empty() => println!(
+				"The database has {} pending migrations, which will be applied when the logger \
+				 starts",
+				pending.len()
+			),
+			Ok(_) => {}
+			Err(err) => problems.push(format!(
+				"failed to check the database's migrations: {}",
+				err
+			)),
+		},
+		Err(err) => problems.push(format!("failed to connect to the database: {}", err)),
+	}
+	if problems.is_empty() {
+		println!("The config is valid");
+		return Ok(());
+	}
+	for problem in &problems {
+		println!("Problem: {}", problem);
+	}
+	Err(eyre!("the config has {} problems", problems.len()))
+}
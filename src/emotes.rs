@@ -0,0 +1,249 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Splits Twitch's `emotes` tag into a row for each emote in a message, so
+//! emote usage can be queried.
+
+use entity::{
+	message_emotes::{
+		ActiveModel as MessageEmoteActiveModel, Column as MessageEmoteColumn,
+		Entity as MessageEmoteEntity,
+	},
+	messages::{Column as MessageColumn, Entity as MessageEntity},
+};
+use sea_orm::{
+	prelude::*,
+	sea_query::{Alias, Expr, OnConflict},
+	ActiveValue::Set,
+	ConnectionTrait, DatabaseConnection, DbBackend, FromQueryResult, QueryOrder, QuerySelect,
+	QueryTrait,
+};
+use serde::Serialize;
+use time::PrimitiveDateTime;
+
+/// How many emotes to insert at one time, to stay under the databases' limits
+/// on how many values one query can have
+const EMOTE_BATCH_SIZE: usize = 1_000;
+/// How many messages to read at one time when backfilling their emotes
+const BACKFILL_BATCH_SIZE: u64 = 10_000;
+
+/// An emote in a message, and how many times it's in it
+pub struct Emote {
+	pub id: String,
+	pub name: String,
+	pub count: usize,
+}
+
+/// Parses an `emotes` tag like `25:0-4,12-16/1902:6-10`, taking each emote's
+/// name from the message. The ranges count characters, not bytes, of the
+/// message without the `\x01ACTION` wrapper of `/me` messages. Emotes with
+/// ranges that are outside of the message are skipped.
+pub fn parse_emotes(tag: &str, message: &str) -> Vec<Emote> {
+	let message = message
+		.strip_prefix("\x01ACTION ")
+		.map_or(message, |action| {
+			action.strip_suffix('\x01').unwrap_or(action)
+		});
+	let chars = message.chars().collect::<Vec<_>>();
+	tag.split('/')
+		.filter_map(|emote| {
+			let (id, ranges) = emote.split_once(':')?;
+			let ranges = ranges
+				.split(',')
+				.map(|range| {
+					let (start, end) = range.split_once('-')?;
+					Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+				})
+				.collect::<Option<Vec<_>>>()?;
+			let (start, end) = *ranges.first()?;
+			Some(Emote {
+				id: id.to_string(),
+				name: chars.get(start..=end)?.iter().collect(),
+				count: ranges.len(),
+			})
+		})
+		.collect()
+}
+
+/// Makes the rows for the emotes in a message
+pub fn emote_rows(
+	id: Uuid,
+	channel: &str,
+	timestamp: PrimitiveDateTime,
+	tag: &str,
+	message: &str,
+) -> Vec<MessageEmoteActiveModel> {
+	parse_emotes(tag, message)
+		.into_iter()
+		.map(|emote| MessageEmoteActiveModel {
+			message_id: Set(id),
+			emote_id: Set(emote.id),
+			channel: Set(channel.to_string()),
+			timestamp: Set(timestamp),
+			name: Set(emote.name),
+			count: Set(emote.count as i32),
+		})
+		.collect()
+}
+
+/// Saves the emotes of messages, replacing what was saved for them before
+pub async fn save_emotes(
+	db: &DatabaseConnection,
+	message_ids: &[Uuid],
+	rows: Vec<MessageEmoteActiveModel>,
+) -> Result<(), DbErr> {
+	for message_ids in message_ids.chunks(EMOTE_BATCH_SIZE) {
+		MessageEmoteEntity::delete_many()
+			.filter(MessageEmoteColumn::MessageId.is_in(message_ids.to_vec()))
+			.exec(db)
+			.await?;
+	}
+	for rows in rows.chunks(EMOTE_BATCH_SIZE) {
+		let query = MessageEmoteEntity::insert_many(rows.to_vec())
+			.on_conflict(
+				OnConflict::columns([MessageEmoteColumn::MessageId, MessageEmoteColumn::EmoteId])
+					.update_columns([MessageEmoteColumn::Name, MessageEmoteColumn::Count])
+					.to_owned(),
+			)
+			.build(db.get_database_backend());
+		db.execute(query).await?;
+	}
+	Ok(())
+}
+
+/// Saves the emotes of every message with an `emotes` tag, going through them
+/// in order of ID. This fills in messages that were stored before their emotes
+/// were, like imported ones. Returns how many emotes were saved.
+pub async fn backfill_emotes(db: &DatabaseConnection) -> Result<usize, DbErr> {
+	let mut last_id = None;
+	let mut saved = 0;
+	loop {
+		let mut query = MessageEntity::find()
+			.filter(MessageColumn::Emotes.is_not_null())
+			.order_by_asc(MessageColumn::Id)
+			.limit(BACKFILL_BATCH_SIZE);
+		if let Some(last_id) = last_id {
+			query = query.filter(MessageColumn::Id.gt(last_id));
+		}
+		let messages = query.all(db).await?;
+		last_id = match messages.last() {
+			Some(message) => Some(message.id),
+			None => break,
+		};
+		let rows = messages
+			.iter()
+			.flat_map(|message| {
+				emote_rows(
+					message.id,
+					&message.channel,
+					message.timestamp,
+					message.emotes.as_deref().unwrap_or_default(),
+					&message.message,
+				)
+			})
+			.collect::<Vec<_>>();
+		let ids = messages
+			.iter()
+			.map(|message| message.id)
+			.collect::<Vec<_>>();
+		saved += rows.len();
+		save_emotes(db, &ids, rows).await?;
+	}
+	Ok(saved)
+}
+
+/// How many times an emote was used
+#[derive(FromQueryResult, Serialize)]
+pub struct EmoteUsage {
+	pub id: String,
+	pub name: String,
+	pub count: i64,
+}
+
+/// Counts how many times each emote was used in a channel, optionally within a
+/// range of time, most used first.
+pub async fn top_emotes(
+	db: &DatabaseConnection,
+	channel: &str,
+	start_time: Option<PrimitiveDateTime>,
+	end_time: Option<PrimitiveDateTime>,
+	limit: u64,
+) -> Result<Vec<EmoteUsage>, DbErr> {
+	// MySQL sums integers into decimals, which have to be cast back
+	let count_type = match db.get_database_backend() {
+		DbBackend::MySql => "SIGNED",
+		_ => "BIGINT",
+	};
+	let mut query = MessageEmoteEntity::find()
+		.select_only()
+		.column_as(MessageEmoteColumn::EmoteId, "id")
+		.column_as(Expr::col(MessageEmoteColumn::Name).max(), "name")
+		.column_as(
+			Expr::col(MessageEmoteColumn::Count)
+				.sum()
+				.cast_as(Alias::new(count_type)),
+			"count",
+		)
+		.filter(MessageEmoteColumn::Channel.eq(channel.to_lowercase()));
+	if let Some(start_time) = start_time {
+		query = query.filter(MessageEmoteColumn::Timestamp.gte(start_time));
+	}
+	if let Some(end_time) = end_time {
+		query = query.filter(MessageEmoteColumn::Timestamp.lte(end_time));
+	}
+	query
+		.group_by(MessageEmoteColumn::EmoteId)
+		.order_by_desc(Expr::col(MessageEmoteColumn::Count).sum())
+		.limit(limit)
+		.into_model::<EmoteUsage>()
+		.all(db)
+		.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn names(tag: &str, message: &str) -> Vec<(String, usize)> {
+		parse_emotes(tag, message)
+			.into_iter()
+			.map(|emote| (emote.name, emote.count))
+			.collect()
+	}
+
+	#[test]
+	fn parses_plain_message() {
+		assert_eq!(names("25:0-4,12-16/1902:6-10", "Kappa Keepo Kappa"), [
+			("Kappa".to_string(), 2),
+			("Keepo".to_string(), 1)
+		]);
+	}
+
+	#[test]
+	fn parses_action_message() {
+		assert_eq!(names("25:0-4/1902:6-10", "\x01ACTION Kappa Keepo\x01"), [
+			("Kappa".to_string(), 1),
+			("Keepo".to_string(), 1)
+		]);
+	}
+
+	#[test]
+	fn counts_characters_not_bytes() {
+		assert_eq!(names("25:8-12", "héllo 🦀 Kappa"), [(
+			"Kappa".to_string(),
+			1
+		)]);
+		assert_eq!(names("25:8-12", "\x01ACTION héllo 🦀 Kappa\x01"), [(
+			"Kappa".to_string(),
+			1
+		)]);
+	}
+
+	#[test]
+	fn skips_out_of_range_emotes() {
+		assert!(names("25:6-10", "Kappa").is_empty());
+	}
+}
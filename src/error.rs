@@ -10,6 +10,7 @@ use axum::{
 };
 use serde_json::json;
 use thiserror::Error as ThisError;
+use uuid::Uuid;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
@@ -17,6 +18,16 @@ pub enum Error {
 	Database(#[from] sea_orm::error::DbErr),
 	#[error("io error: {0}")]
 	Io(#[from] std::io::Error),
+	#[error("message {0} not found")]
+	MessageNotFound(Uuid),
+	#[error("stream {0} not found")]
+	StreamNotFound(String),
+	#[error("invalid date")]
+	InvalidDate,
+	#[error("invalid id: {0}")]
+	InvalidId(String),
+	#[error("archive error: {0}")]
+	Archive(String),
 }
 
 impl IntoResponse for Error {
@@ -24,6 +35,13 @@ impl IntoResponse for Error {
 		let (status, error_msg) = match self {
 			Self::Database(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
 			Self::Io(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+			Self::MessageNotFound(id) => {
+				(StatusCode::NOT_FOUND, format!("message {} not found", id))
+			}
+			Self::StreamNotFound(id) => (StatusCode::NOT_FOUND, format!("stream {} not found", id)),
+			Self::InvalidDate => (StatusCode::BAD_REQUEST, "invalid date".to_string()),
+			Self::InvalidId(id) => (StatusCode::BAD_REQUEST, format!("invalid id: {}", id)),
+			Self::Archive(err) => (StatusCode::INTERNAL_SERVER_ERROR, err),
 		};
 		let body = Json(json!({
 			"status": status.as_u16(),
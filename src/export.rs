@@ -0,0 +1,69 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+	config::Config, open_database, reconstruct, rollup::MAX_MESSAGES_PER_PAGE, search::parse_time,
+	server,
+};
+use color_eyre::eyre::{Result, WrapErr};
+use entity::messages::Column as MessageColumn;
+use sea_orm::{prelude::*, QueryOrder};
+use std::{
+	fs::File,
+	io::{BufWriter, Write},
+	path::PathBuf,
+};
+
+/// Exports a channel's messages as the raw IRC lines they were received as,
+/// along with the `CLEARMSG`s of deleted messages.
+pub async fn export(
+	config: &Config,
+	channel: String,
+	start: Option<String>,
+	end: Option<String>,
+	output: Option<PathBuf>,
+) -> Result<()> {
+	let start_time = parse_time(start.as_deref())?;
+	let end_time = parse_time(end.as_deref())?;
+	let mut output: Box<dyn Write> = match output {
+		Some(path) => {
+			Box::new(BufWriter::new(File::create(&path).wrap_err_with(|| {
+				format!("failed to create {}", path.display())
+			})?))
+		}
+		None => Box::new(std::io::stdout().lock()),
+	};
+	let db = open_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+
+	let mut message_pages = server::search_query(&channel, &[], start_time, end_time)
+		.order_by_asc(MessageColumn::Timestamp)
+		.paginate(&db, MAX_MESSAGES_PER_PAGE);
+	let mut exported = 0_usize;
+	while let Some(messages) = message_pages
+		.fetch_and_next()
+		.await
+		.wrap_err("failed to get messages")?
+	{
+		for message in messages {
+			writeln!(output, "{}", reconstruct::privmsg_line(&message))
+				.wrap_err("failed to write message")?;
+			if let Some(tags) = reconstruct::clearmsg_tags(&message) {
+				writeln!(
+					output,
+					"{}",
+					reconstruct::tagged_line(&tags, &reconstruct::clearmsg_body(&message))
+				)
+				.wrap_err("failed to write message")?;
+			}
+			exported += 1;
+		}
+	}
+	output.flush().wrap_err("failed to write messages")?;
+	info!("Exported {} messages from #{}", exported, channel);
+	Ok(())
+}
@@ -0,0 +1,675 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+	badges::Badges,
+	cli::ImportFormat,
+	config::{Config, RollupFormat},
+	connect_database, emotes, partitions, reprocess,
+	rollup::{self, LogFileName, RollupPeriod, TextLogMessage},
+};
+use ahash::{AHashMap, AHashSet};
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use entity::messages::{
+	ActiveModel as MessageActiveModel, Column as MessageColumn, Entity as MessageEntity,
+};
+use sea_orm::{
+	prelude::*, sea_query::OnConflict, ActiveValue::Set, ConnectionTrait, DatabaseConnection,
+	EntityTrait, FromQueryResult, QuerySelect, QueryTrait,
+};
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use time::{
+	format_description::well_known::Rfc3339, macros::format_description, Date, Duration,
+	OffsetDateTime, PrimitiveDateTime, Time, UtcOffset,
+};
+use time_tz::Tz;
+use tokio::{
+	fs::File,
+	io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader, Lines},
+	sync::broadcast,
+};
+
+/// The namespace of the IDs made up for messages imported from text rollups.
+/// The same message always gets the same ID, so importing twice is harmless.
+const ROLLUP_NAMESPACE: Uuid = Uuid::from_u128(0x5c1f_0a7e_93d4_4b8e_a1e2_7f1c_3d4b_9e60);
+/// The namespace of the IDs made up for messages imported from Chatterino logs
+const CHATTERINO_NAMESPACE: Uuid = Uuid::from_u128(0x0b3e_62d9_7a15_4c0f_8e47_d2a9_51c6_f38b);
+
+/// How many messages to insert at one time
+const IMPORT_BATCH_SIZE: usize = 1_000;
+
+/// Opens a file to be read, decompressing it if it's gzipped or zstd
+/// compressed.
+pub async fn open_reader(path: &Path) -> Result<Box<dyn AsyncBufRead + Send + Unpin>> {
+	let file = BufReader::new(File::open(path).await?);
+	Ok(
+		match path.extension().and_then(|extension| extension.to_str()) {
+			Some("gz") => Box::new(BufReader::new(GzipDecoder::new(file))),
+			Some("zst") => Box::new(BufReader::new(ZstdDecoder::new(file))),
+			_ => Box::new(file),
+		},
+	)
+}
+
+/// Opens a file to be read line by line, decompressing it if it's compressed.
+pub async fn open_lines(path: &Path) -> Result<Lines<Box<dyn AsyncBufRead + Send + Unpin>>> {
+	Ok(open_reader(path).await?.lines())
+}
+
+/// Makes up IDs for messages that don't have one, from what they say and
+/// how many times the same thing was said.
+struct SyntheticIds {
+	namespace: Uuid,
+	seen: AHashMap<String, usize>,
+}
+
+impl SyntheticIds {
+	fn new(namespace: Uuid) -> Self {
+		Self {
+			namespace,
+			seen: AHashMap::new(),
+		}
+	}
+
+	fn next(
+		&mut self,
+		channel: &str,
+		timestamp: PrimitiveDateTime,
+		username: &str,
+		message: &str,
+	) -> Uuid {
+		let key = format!("{}\n{}\n{}\n{}", channel, timestamp, username, message);
+		// The same person can say the same thing more than once in a second
+		let occurrence = self.seen.entry(key.clone()).or_default();
+		*occurrence += 1;
+		Uuid::new_v5(
+			&self.namespace,
+			format!("{}\n{}", key, occurrence).as_bytes(),
+		)
+	}
+}
+
+#[derive(FromQueryResult)]
+struct IdQuery {
+	id: Uuid,
+}
+
+/// Inserts a batch of imported messages, skipping any that are already in the
+/// database. IDs aren't unique in a partitioned table, so there the messages
+/// that are already in it are looked up first.
+async fn insert_batch(
+	db: &DatabaseConnection,
+	batch: &mut Vec<MessageActiveModel>,
+	partitioned: bool,
+) -> Result<u64> {
+	if partitioned && !batch.is_empty() {
+		let existing = MessageEntity::find()
+			.select_only()
+			.column(MessageColumn::Id)
+			.filter(
+				MessageColumn::Id.is_in(batch.iter().map(|message| message.id.clone().unwrap())),
+			)
+			.into_model::<IdQuery>()
+			.all(db)
+			.await
+			.wrap_err("failed to get existing messages")?
+			.into_iter()
+			.map(|message| message.id)
+			.collect::<AHashSet<_>>();
+		batch.retain(|message| !existing.contains(message.id.as_ref()));
+	}
+	if batch.is_empty() {
+		return Ok(0);
+	}
+	let mut insert = MessageEntity::insert_many(batch.drain(..));
+	if !partitioned {
+		insert = insert.on_conflict(
+			OnConflict::column(MessageColumn::Id)
+				.do_nothing()
+				.to_owned(),
+		);
+	}
+	let query = insert.build(db.get_database_backend());
+	Ok(db
+		.execute(query)
+		.await
+		.wrap_err("failed to insert messages")?
+		.rows_affected())
+}
+
+/// Borrows the room ID from any message we already have in a channel, for
+/// logs that don't have it.
+async fn known_room_id(db: &DatabaseConnection, channel: &str) -> Result<i64> {
+	Ok(MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(channel))
+		.one(db)
+		.await
+		.wrap_err("failed to look up room ID")?
+		.map(|message| message.room_id)
+		.unwrap_or_default())
+}
+
+/// A message from a log without Twitch's tags, so all we know is who said what
+/// and when. User IDs are left as 0.
+fn untagged_message(
+	id: Uuid,
+	channel: &str,
+	room_id: i64,
+	username: String,
+	message: String,
+	timestamp: PrimitiveDateTime,
+	deleted_at: Option<PrimitiveDateTime>,
+) -> MessageActiveModel {
+	MessageActiveModel {
+		id: Set(id),
+		channel: Set(channel.to_string()),
+		room_id: Set(room_id),
+		user_id: Set(0),
+		username: Set(username),
+		message: Set(message),
+		timestamp: Set(timestamp),
+		deleted: Set(deleted_at.is_some()),
+		deleted_at: Set(deleted_at),
+		replying_to: Set(None),
+		subscriber: Set(false),
+		moderator: Set(false),
+		vip: Set(false),
+		emotes: Set(None),
+		badges: Set(None),
+		user_type: Set(None),
+		badge_info: Set(None),
+		sub_months: Set(None),
+		bits_tier: Set(None),
+	}
+}
+
+/// Finds every file in the given paths whose name passes the filter, looking
+/// through directories.
+async fn find_files(paths: Vec<PathBuf>, filter: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	let mut directories = Vec::new();
+	for path in paths {
+		if path.is_dir() {
+			directories.push(path);
+		} else {
+			files.push(path);
+		}
+	}
+	while let Some(directory) = directories.pop() {
+		let mut entries = tokio::fs::read_dir(&directory)
+			.await
+			.wrap_err_with(|| format!("failed to read {}", directory.display()))?;
+		while let Some(entry) = entries.next_entry().await? {
+			let file_type = entry.file_type().await?;
+			if file_type.is_dir() {
+				directories.push(entry.path());
+			} else if file_type.is_file()
+				&& entry.file_name().to_str().map(&filter).unwrap_or(false)
+			{
+				files.push(entry.path());
+			}
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+/// Imports text rollups back into the database. If no paths are given,
+/// everything in the rollup directory is imported.
+pub async fn import_rollups(config: &Config, paths: Vec<PathBuf>) -> Result<()> {
+	let db = connect_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+	let partitioned = partitions::is_partitioned(&db).await?;
+	let paths = if paths.is_empty() {
+		vec![config.rollup_dir.clone()]
+	} else {
+		paths
+	};
+	let files = find_files(paths, |file_name| {
+		matches!(
+			rollup::parse_log_file_name(file_name),
+			Some(LogFileName {
+				format: RollupFormat::Text,
+				..
+			})
+		)
+	})
+	.await?;
+	for file in files {
+		import_rollup_file(&db, config, &file, partitioned)
+			.await
+			.wrap_err_with(|| format!("failed to import {}", file.display()))?;
+	}
+	Ok(())
+}
+
+/// Reads the messages back out of a text rollup, giving each the same made up
+/// ID every time.
+pub struct TextRollupReader {
+	path: PathBuf,
+	pub channel: String,
+	pub date: Date,
+	period: RollupPeriod,
+	zone: &'static Tz,
+	lines: Lines<Box<dyn AsyncBufRead + Send + Unpin>>,
+	ids: SyntheticIds,
+	line_date: Date,
+	last_timestamp: Option<PrimitiveDateTime>,
+}
+
+impl TextRollupReader {
+	pub async fn open(config: &Config, path: &Path) -> Result<Self> {
+		let LogFileName {
+			channel,
+			date,
+			period,
+			..
+		} = path
+			.file_name()
+			.and_then(|file_name| file_name.to_str())
+			.and_then(rollup::parse_log_file_name)
+			.ok_or_else(|| eyre!("file isn't named like a rollup"))?;
+		Ok(Self {
+			path: path.to_path_buf(),
+			zone: config.channel_timezone(&channel),
+			channel,
+			date,
+			period,
+			lines: open_lines(path).await.wrap_err("failed to open file")?,
+			ids: SyntheticIds::new(ROLLUP_NAMESPACE),
+			line_date: date,
+			last_timestamp: None,
+		})
+	}
+
+	/// Reads the next message, skipping malformed lines
+	pub async fn next_message(&mut self) -> Result<Option<(Uuid, TextLogMessage)>> {
+		while let Some(line) = self
+			.lines
+			.next_line()
+			.await
+			.wrap_err("failed to read line")?
+		{
+			let mut message = match rollup::parse_text_log_line(&line, self.line_date, self.zone) {
+				Some(message) => message,
+				None => {
+					if !line.trim().is_empty() {
+						warn!(
+							"skipping malformed line in {}: {}",
+							self.path.display(),
+							line
+						);
+					}
+					continue;
+				}
+			};
+			// Streams can run past midnight, which shows up as the time going
+			// far backwards
+			if matches!(self.period, RollupPeriod::Stream(_))
+				&& self
+					.last_timestamp
+					.is_some_and(|last| message.timestamp + Duration::hours(12) < last)
+			{
+				self.line_date = self.line_date.next_day().expect("ran out of days");
+				message = rollup::parse_text_log_line(&line, self.line_date, self.zone)
+					.expect("failed to parse line that was already parsed");
+			}
+			self.last_timestamp = Some(message.timestamp);
+			let id = self.ids.next(
+				&self.channel,
+				message.timestamp,
+				&message.username,
+				&message.message,
+			);
+			return Ok(Some((id, message)));
+		}
+		Ok(None)
+	}
+}
+
+async fn import_rollup_file(
+	db: &DatabaseConnection,
+	config: &Config,
+	path: &Path,
+	partitioned: bool,
+) -> Result<()> {
+	let mut reader = TextRollupReader::open(config, path).await?;
+	let room_id = known_room_id(db, &reader.channel).await?;
+
+	let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+	let mut read = 0_usize;
+	let mut inserted = 0_u64;
+	while let Some((id, message)) = reader.next_message().await? {
+		batch.push(untagged_message(
+			id,
+			&reader.channel,
+			room_id,
+			message.username,
+			message.message,
+			message.timestamp,
+			message.deleted_at,
+		));
+		read += 1;
+		if batch.len() >= IMPORT_BATCH_SIZE {
+			inserted += insert_batch(db, &mut batch, partitioned).await?;
+		}
+	}
+	inserted += insert_batch(db, &mut batch, partitioned).await?;
+	info!(
+		"Imported {} of {} messages from {} for #{} on {}",
+		inserted,
+		read,
+		path.display(),
+		reader.channel,
+		reader.date
+	);
+	Ok(())
+}
+
+/// Imports chat history from another logger's files.
+pub async fn import(
+	config: &Config,
+	format: ImportFormat,
+	paths: Vec<PathBuf>,
+	utc_offset: UtcOffset,
+) -> Result<()> {
+	let db = connect_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+	let files = find_files(paths, |file_name| match format {
+		ImportFormat::Justlog => file_name.ends_with(".txt") || file_name.ends_with(".txt.gz"),
+		ImportFormat::Chatterino => parse_chatterino_file_name(file_name).is_some(),
+		ImportFormat::TwitchVod => file_name.ends_with(".json") || file_name.ends_with(".json.gz"),
+	})
+	.await?;
+	let partitioned = partitions::is_partitioned(&db).await?;
+	// Nobody is watching imported messages live
+	let (live_tx, _) = broadcast::channel(1);
+	for file in files {
+		match format {
+			// justlog keeps the raw IRC lines, so they can go through the same
+			// processing as when they were first received
+			ImportFormat::Justlog => {
+				reprocess::reprocess_file(&db, &live_tx, &file, partitioned).await
+			}
+			ImportFormat::Chatterino => {
+				import_chatterino_file(&db, &file, utc_offset, partitioned).await
+			}
+			ImportFormat::TwitchVod => import_twitch_vod_file(&db, &file, partitioned).await,
+		}
+		.wrap_err_with(|| format!("failed to import {}", file.display()))?;
+	}
+	Ok(())
+}
+
+/// Gets the channel and date of a Chatterino log from its name, which is in
+/// the format `channel-YYYY-MM-DD.log`.
+fn parse_chatterino_file_name(file_name: &str) -> Option<(String, Date)> {
+	let (channel, date) = file_name.strip_suffix(".log")?.split_once('-')?;
+	let date = Date::parse(date, format_description!("[year]-[month]-[day]")).ok()?;
+	Some((channel.to_lowercase(), date))
+}
+
+/// Parses a chat message from a Chatterino log, which looks like
+/// `[HH:MM:SS] username: message`, or `[HH:MM:SS] DisplayName (login):
+/// message` for localized names. System messages are skipped.
+fn parse_chatterino_line(line: &str) -> Option<(Time, String, String)> {
+	let (time, rest) = line.strip_prefix('[')?.split_once("] ")?;
+	let time = Time::parse(time, format_description!("[hour]:[minute]:[second]")).ok()?;
+	let (name, message) = rest.split_once(": ")?;
+	let is_login =
+		|name: &str| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+	let username = match name.split_once(" (") {
+		Some((display_name, login)) => {
+			let login = login.strip_suffix(')')?;
+			if display_name.contains(' ') || !is_login(login) {
+				return None;
+			}
+			login
+		}
+		None if is_login(name) => name,
+		None => return None,
+	};
+	Some((time, username.to_lowercase(), message.to_string()))
+}
+
+async fn import_chatterino_file(
+	db: &DatabaseConnection,
+	path: &Path,
+	utc_offset: UtcOffset,
+	partitioned: bool,
+) -> Result<()> {
+	let (channel, date) = path
+		.file_name()
+		.and_then(|file_name| file_name.to_str())
+		.and_then(parse_chatterino_file_name)
+		.ok_or_else(|| eyre!("file isn't named like a Chatterino log"))?;
+	let room_id = known_room_id(db, &channel).await?;
+
+	let mut lines = open_lines(path).await.wrap_err("failed to open file")?;
+	let mut ids = SyntheticIds::new(CHATTERINO_NAMESPACE);
+	let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+	let mut read = 0_usize;
+	let mut inserted = 0_u64;
+	while let Some(line) = lines.next_line().await.wrap_err("failed to read line")? {
+		// Lines starting with # mark when logging started and stopped
+		if line.starts_with('#') {
+			continue;
+		}
+		let (time, username, message) = match parse_chatterino_line(&line) {
+			Some(parsed) => parsed,
+			None => continue,
+		};
+		// Chatterino logs in the local time of whoever was running it
+		let timestamp = date
+			.with_time(time)
+			.assume_offset(utc_offset)
+			.to_offset(UtcOffset::UTC);
+		let timestamp = PrimitiveDateTime::new(timestamp.date(), timestamp.time());
+		let id = ids.next(&channel, timestamp, &username, &message);
+		batch.push(untagged_message(
+			id, &channel, room_id, username, message, timestamp, None,
+		));
+		read += 1;
+		if batch.len() >= IMPORT_BATCH_SIZE {
+			inserted += insert_batch(db, &mut batch, partitioned).await?;
+		}
+	}
+	inserted += insert_batch(db, &mut batch, partitioned).await?;
+	info!(
+		"Imported {} of {} messages from {} for #{} on {}",
+		inserted,
+		read,
+		path.display(),
+		channel,
+		date
+	);
+	Ok(())
+}
+
+/// Twitch's IDs are sometimes strings and sometimes numbers in chat downloads
+fn deserialize_twitch_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum TwitchId {
+		Number(i64),
+		String(String),
+	}
+	match TwitchId::deserialize(deserializer)? {
+		TwitchId::Number(id) => Ok(id),
+		TwitchId::String(id) => id.parse().map_err(serde::de::Error::custom),
+	}
+}
+
+/// A chat download of a VOD, as made by tools like TwitchDownloader
+#[derive(Deserialize)]
+struct VodChat {
+	streamer: VodStreamer,
+	comments: Vec<VodComment>,
+}
+
+#[derive(Deserialize)]
+struct VodStreamer {
+	name: String,
+	#[serde(deserialize_with = "deserialize_twitch_id")]
+	id: i64,
+}
+
+#[derive(Deserialize)]
+struct VodComment {
+	#[serde(rename = "_id")]
+	id: Uuid,
+	created_at: String,
+	commenter: VodCommenter,
+	message: VodMessage,
+}
+
+#[derive(Deserialize)]
+struct VodCommenter {
+	#[serde(rename = "_id", deserialize_with = "deserialize_twitch_id")]
+	id: i64,
+	name: String,
+}
+
+#[derive(Deserialize)]
+struct VodMessage {
+	body: String,
+	#[serde(default)]
+	user_badges: Vec<VodBadge>,
+	#[serde(default)]
+	emoticons: Vec<VodEmoticon>,
+}
+
+#[derive(Deserialize)]
+struct VodBadge {
+	#[serde(rename = "_id")]
+	id: String,
+	version: String,
+}
+
+#[derive(Deserialize)]
+struct VodEmoticon {
+	#[serde(rename = "_id")]
+	id: String,
+	begin: usize,
+	end: usize,
+}
+
+/// Rebuilds the `emotes` tag Twitch would have sent with a message, like
+/// `25:0-4,12-16/1902:6-10`.
+fn vod_emotes_tag(emoticons: &[VodEmoticon]) -> Option<String> {
+	let mut emotes = Vec::<(&str, Vec<String>)>::new();
+	for emoticon in emoticons {
+		let range = format!("{}-{}", emoticon.begin, emoticon.end);
+		match emotes.iter_mut().find(|(id, _)| *id == emoticon.id) {
+			Some((_, ranges)) => ranges.push(range),
+			None => emotes.push((&emoticon.id, vec![range])),
+		}
+	}
+	if emotes.is_empty() {
+		return None;
+	}
+	Some(
+		emotes
+			.into_iter()
+			.map(|(id, ranges)| format!("{}:{}", id, ranges.join(",")))
+			.collect::<Vec<_>>()
+			.join("/"),
+	)
+}
+
+async fn import_twitch_vod_file(
+	db: &DatabaseConnection,
+	path: &Path,
+	partitioned: bool,
+) -> Result<()> {
+	let mut json = String::new();
+	open_reader(path)
+		.await
+		.wrap_err("failed to open file")?
+		.read_to_string(&mut json)
+		.await
+		.wrap_err("failed to read file")?;
+	let chat = serde_json::from_str::<VodChat>(&json).wrap_err("failed to parse chat download")?;
+	let channel = chat.streamer.name.to_lowercase();
+
+	let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+	let (mut emote_batch, mut emote_messages) = (Vec::new(), Vec::new());
+	let mut inserted = 0_u64;
+	let read = chat.comments.len();
+	for comment in chat.comments {
+		let timestamp = OffsetDateTime::parse(&comment.created_at, &Rfc3339)
+			.wrap_err_with(|| format!("failed to parse time of comment {}", comment.id))?
+			.to_offset(UtcOffset::UTC);
+		let badges = comment
+			.message
+			.user_badges
+			.iter()
+			.map(|badge| format!("{}/{}", badge.id, badge.version))
+			.collect::<Vec<_>>()
+			.join(",");
+		// VODs don't keep badge info, so how long they've been subscribed isn't known
+		let parsed_badges = Badges::parse(Some(&badges), None);
+		let timestamp = PrimitiveDateTime::new(timestamp.date(), timestamp.time());
+		let emotes_tag = vod_emotes_tag(&comment.message.emoticons);
+		emote_messages.push(comment.id);
+		if let Some(tag) = &emotes_tag {
+			emote_batch.extend(emotes::emote_rows(
+				comment.id,
+				&channel,
+				timestamp,
+				tag,
+				&comment.message.body,
+			));
+		}
+		batch.push(MessageActiveModel {
+			id: Set(comment.id),
+			channel: Set(channel.clone()),
+			room_id: Set(chat.streamer.id),
+			user_id: Set(comment.commenter.id),
+			username: Set(comment.commenter.name.to_lowercase()),
+			message: Set(comment.message.body.clone()),
+			timestamp: Set(timestamp),
+			deleted: Set(false),
+			deleted_at: Set(None),
+			replying_to: Set(None),
+			subscriber: Set(parsed_badges.subscriber),
+			moderator: Set(parsed_badges.moderator),
+			vip: Set(parsed_badges.vip),
+			emotes: Set(emotes_tag),
+			badges: Set(Some(badges).filter(|badges| !badges.is_empty())),
+			user_type: Set(None),
+			badge_info: Set(None),
+			sub_months: Set(None),
+			bits_tier: Set(parsed_badges.bits_tier),
+		});
+		if batch.len() >= IMPORT_BATCH_SIZE {
+			inserted += insert_batch(db, &mut batch, partitioned).await?;
+			emotes::save_emotes(
+				db,
+				&std::mem::take(&mut emote_messages),
+				std::mem::take(&mut emote_batch),
+			)
+			.await
+			.wrap_err("failed to insert emotes")?;
+		}
+	}
+	inserted += insert_batch(db, &mut batch, partitioned).await?;
+	emotes::save_emotes(db, &emote_messages, emote_batch)
+		.await
+		.wrap_err("failed to insert emotes")?;
+	info!(
+		"Imported {} of {} messages from {} for #{}",
+		inserted,
+		read,
+		path.display(),
+		channel
+	);
+	Ok(())
+}
@@ -0,0 +1,462 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Routes compatible with the HTTP API of [justlog](https://github.com/gempir/justlog),
+//! so that existing log viewers can read our logs.
+
+use crate::{
+	error::{Error, Result},
+	reconstruct,
+	rollup::MAX_MESSAGES_PER_PAGE,
+	server::AppState,
+};
+use async_stream::try_stream;
+use axum::{
+	body::StreamBody,
+	extract::{Path, State},
+	http::{header, StatusCode},
+	response::{IntoResponse, Redirect, Response},
+	routing::get,
+	Json, Router,
+};
+use axum_extra::extract::Query;
+use entity::messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message};
+use futures_util::Stream;
+use sea_orm::{
+	prelude::*, sea_query::Expr, ConnectionTrait, DatabaseConnection, DbBackend, DeriveColumn,
+	EntityTrait, EnumIter, QueryOrder, QuerySelect,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use time::{
+	format_description::well_known::Rfc3339, macros::format_description, Date, Duration, Month,
+	Time,
+};
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+enum ChannelQueryAs {
+	Channel,
+	RoomId,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+enum MonthQueryAs {
+	Year,
+	Month,
+}
+
+#[derive(Deserialize)]
+struct LogParams {
+	json: Option<String>,
+	raw: Option<String>,
+	reverse: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+	channel: Option<String>,
+	#[serde(rename = "channelid")]
+	channel_id: Option<String>,
+	user: Option<String>,
+	#[serde(rename = "userid")]
+	user_id: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum LogFormat {
+	Text,
+	Json,
+	Raw,
+}
+
+impl LogParams {
+	fn format(&self) -> LogFormat {
+		if self.json.is_some() {
+			LogFormat::Json
+		} else if self.raw.is_some() {
+			LogFormat::Raw
+		} else {
+			LogFormat::Text
+		}
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JustlogMessage<'a> {
+	text: &'a str,
+	system_text: &'a str,
+	username: &'a str,
+	display_name: &'a str,
+	channel: &'a str,
+	timestamp: String,
+	id: String,
+	#[serde(rename = "type")]
+	kind: u8,
+	raw: String,
+	tags: BTreeMap<&'static str, String>,
+}
+
+/// The message type justlog uses for `PRIVMSG`
+const PRIVMSG_TYPE: u8 = 1;
+
+fn format_message(message: &Message, format: LogFormat) -> String {
+	match format {
+		LogFormat::Text => format!(
+			"[{}] #{} {}: {}\n",
+			message
+				.timestamp
+				.format(format_description!(
+					"[year]-[month]-[day] [hour]:[minute]:[second]"
+				))
+				.expect("failed to format time"),
+			message.channel,
+			message.username,
+			message.message
+		),
+		LogFormat::Raw => format!("{}\n", reconstruct::privmsg_line(message)),
+		LogFormat::Json => serde_json::to_string(&JustlogMessage {
+			text: &message.message,
+			system_text: "",
+			username: &message.username,
+			display_name: &message.username,
+			channel: &message.channel,
+			timestamp: message
+				.timestamp
+				.assume_utc()
+				.format(&Rfc3339)
+				.expect("failed to format time"),
+			id: message.id.to_string(),
+			kind: PRIVMSG_TYPE,
+			raw: reconstruct::privmsg_line(message),
+			tags: reconstruct::message_tags(message).into_iter().collect(),
+		})
+		.expect("failed to serialize message"),
+	}
+}
+
+fn log_stream(
+	db: DatabaseConnection,
+	query: Select<MessageEntity>,
+	format: LogFormat,
+) -> impl Stream<Item = Result<String>> {
+	try_stream! {
+		if let LogFormat::Json = format {
+			yield "{\"messages\":[".to_string();
+		}
+		let mut first = true;
+		let mut message_pages = query.paginate(&db, MAX_MESSAGES_PER_PAGE);
+		while let Some(messages) = message_pages.fetch_and_next().await.map_err(Error::from)? {
+			for message in messages {
+				let formatted = format_message(&message, format);
+				if let LogFormat::Json = format {
+					if !first {
+						yield ",".to_string();
+					}
+				}
+				first = false;
+				yield formatted;
+			}
+		}
+		if let LogFormat::Json = format {
+			yield "]}".to_string();
+		}
+	}
+}
+
+fn log_response(
+	db: DatabaseConnection,
+	query: Select<MessageEntity>,
+	params: LogParams,
+) -> Response {
+	let format = params.format();
+	let query = if params.reverse.is_some() {
+		query.order_by_desc(MessageColumn::Timestamp)
+	} else {
+		query.order_by_asc(MessageColumn::Timestamp)
+	};
+	let content_type = match format {
+		LogFormat::Json => "application/json",
+		LogFormat::Text | LogFormat::Raw => "text/plain; charset=utf-8",
+	};
+	(
+		StatusCode::OK,
+		[(header::CONTENT_TYPE, content_type)],
+		StreamBody::new(log_stream(db, query, format)),
+	)
+		.into_response()
+}
+
+fn parse_id(id: &str) -> Result<i64> {
+	id.parse::<i64>()
+		.map_err(|_| Error::InvalidId(id.to_string()))
+}
+
+/// Gets the filter for the channel in the path, which is either given by name
+/// (`/channel/:channel`) or by ID (`/channelid/:channelid`).
+fn channel_filter(params: &HashMap<String, String>) -> Result<migration::SimpleExpr> {
+	match (params.get("channel"), params.get("channelid")) {
+		(Some(channel), _) => Ok(MessageColumn::Channel.eq(channel.to_lowercase())),
+		(None, Some(id)) => Ok(MessageColumn::RoomId.eq(parse_id(id)?)),
+		(None, None) => unreachable!("route without a channel"),
+	}
+}
+
+/// Gets the filter for the user in the path, which is either given by name
+/// (`/user/:user`) or by ID (`/userid/:userid`).
+fn user_filter(params: &HashMap<String, String>) -> Result<migration::SimpleExpr> {
+	match (params.get("user"), params.get("userid")) {
+		(Some(user), _) => Ok(MessageColumn::Username.eq(user.to_lowercase())),
+		(None, Some(id)) => Ok(MessageColumn::UserId.eq(parse_id(id)?)),
+		(None, None) => unreachable!("route without a user"),
+	}
+}
+
+fn path_prefix(params: &HashMap<String, String>) -> String {
+	let mut prefix = match (params.get("channel"), params.get("channelid")) {
+		(Some(channel), _) => format!("/channel/{}", channel),
+		(None, Some(id)) => format!("/channelid/{}", id),
+		(None, None) => unreachable!("route without a channel"),
+	};
+	match (params.get("user"), params.get("userid")) {
+		(Some(user), _) => prefix.push_str(&format!("/user/{}", user)),
+		(None, Some(id)) => prefix.push_str(&format!("/userid/{}", id)),
+		(None, None) => {}
+	}
+	prefix
+}
+
+fn parse_date(params: &HashMap<String, String>) -> Result<Date> {
+	let year = params
+		.get("year")
+		.and_then(|year| year.parse::<i32>().ok())
+		.ok_or(Error::InvalidDate)?;
+	let month = params
+		.get("month")
+		.and_then(|month| month.parse::<u8>().ok())
+		.and_then(|month| Month::try_from(month).ok())
+		.ok_or(Error::InvalidDate)?;
+	let day = match params.get("day") {
+		Some(day) => day.parse::<u8>().map_err(|_| Error::InvalidDate)?,
+		None => 1,
+	};
+	Date::from_calendar_date(year, month, day).map_err(|_| Error::InvalidDate)
+}
+
+fn first_of_next_month(date: Date) -> Date {
+	let year = match date.month() {
+		Month::December => date.year() + 1,
+		_ => date.year(),
+	};
+	Date::from_calendar_date(year, date.month().next(), 1)
+		.expect("first of the month is always valid")
+}
+
+async fn user_logs(
+	State(db): State<DatabaseConnection>,
+	Path(path): Path<HashMap<String, String>>,
+	Query(params): Query<LogParams>,
+) -> Result<Response> {
+	let start_of_month = parse_date(&path)?;
+	let query = MessageEntity::find()
+		.filter(channel_filter(&path)?)
+		.filter(user_filter(&path)?)
+		.filter(MessageColumn::Timestamp.gte(start_of_month.with_time(Time::MIDNIGHT)))
+		.filter(
+			MessageColumn::Timestamp
+				.lt(first_of_next_month(start_of_month).with_time(Time::MIDNIGHT)),
+		);
+	Ok(log_response(db, query, params))
+}
+
+async fn channel_logs(
+	State(db): State<DatabaseConnection>,
+	Path(path): Path<HashMap<String, String>>,
+	Query(params): Query<LogParams>,
+) -> Result<Response> {
+	let start_of_day = parse_date(&path)?.with_time(Time::MIDNIGHT);
+	let query = MessageEntity::find()
+		.filter(channel_filter(&path)?)
+		.filter(MessageColumn::Timestamp.gte(start_of_day))
+		.filter(MessageColumn::Timestamp.lt(start_of_day + Duration::days(1)));
+	Ok(log_response(db, query, params))
+}
+
+/// Redirects to the logs of the month a user last chatted in.
+async fn latest_user_logs(
+	State(db): State<DatabaseConnection>,
+	Path(path): Path<HashMap<String, String>>,
+) -> Result<Response> {
+	let latest = MessageEntity::find()
+		.filter(channel_filter(&path)?)
+		.filter(user_filter(&path)?)
+		.order_by_desc(MessageColumn::Timestamp)
+		.one(&db)
+		.await?;
+	Ok(match latest {
+		Some(message) => Redirect::to(&format!(
+			"{}/{}/{}",
+			path_prefix(&path),
+			message.timestamp.year(),
+			message.timestamp.month() as u8
+		))
+		.into_response(),
+		None => (StatusCode::NOT_FOUND, "could not load logs").into_response(),
+	})
+}
+
+/// Redirects to the logs of the last day a channel was logged on.
+async fn latest_channel_logs(
+	State(db): State<DatabaseConnection>,
+	Path(path): Path<HashMap<String, String>>,
+) -> Result<Response> {
+	let latest = MessageEntity::find()
+		.filter(channel_filter(&path)?)
+		.order_by_desc(MessageColumn::Timestamp)
+		.one(&db)
+		.await?;
+	Ok(match latest {
+		Some(message) => Redirect::to(&format!(
+			"{}/{}/{}/{}",
+			path_prefix(&path),
+			message.timestamp.year(),
+			message.timestamp.month() as u8,
+			message.timestamp.day()
+		))
+		.into_response(),
+		None => (StatusCode::NOT_FOUND, "could not load logs").into_response(),
+	})
+}
+
+async fn channels(State(db): State<DatabaseConnection>) -> Result<impl IntoResponse> {
+	let channels: Vec<(String, i64)> = MessageEntity::find()
+		.select_only()
+		.column_as(MessageColumn::Channel, ChannelQueryAs::Channel)
+		.column_as(MessageColumn::RoomId, ChannelQueryAs::RoomId)
+		.group_by(MessageColumn::Channel)
+		.group_by(MessageColumn::RoomId)
+		.order_by_asc(MessageColumn::Channel)
+		.into_values::<_, ChannelQueryAs>()
+		.all(&db)
+		.await?;
+	Ok(Json(json!({
+		"channels": channels
+			.into_iter()
+			.map(|(name, id)| json!({ "userID": id.to_string(), "name": name }))
+			.collect::<Vec<_>>(),
+	})))
+}
+
+/// Lists the months a user has logs for, or the days a channel has logs for,
+/// newest first.
+async fn list(
+	State(db): State<DatabaseConnection>,
+	Query(params): Query<ListParams>,
+) -> Result<Response> {
+	let mut path = HashMap::new();
+	match (params.channel, params.channel_id) {
+		(Some(channel), _) => path.insert("channel".to_string(), channel),
+		(None, Some(id)) => path.insert("channelid".to_string(), id),
+		(None, None) => {
+			return Ok((StatusCode::BAD_REQUEST, "missing channel").into_response());
+		}
+	};
+	match (params.user, params.user_id) {
+		(Some(user), _) => path.insert("user".to_string(), user),
+		(None, Some(id)) => path.insert("userid".to_string(), id),
+		(None, None) => None,
+	};
+
+	let available_logs = if path.contains_key("user") || path.contains_key("userid") {
+		// Grouped in the database, as a user can have a lot of messages
+		let (year, month) = match db.get_database_backend() {
+			DbBackend::MySql => (
+				"CAST(YEAR(`timestamp`) AS SIGNED)",
+				"CAST(MONTH(`timestamp`) AS SIGNED)",
+			),
+			DbBackend::Postgres => (
+				r#"CAST(EXTRACT(YEAR FROM "timestamp") AS BIGINT)"#,
+				r#"CAST(EXTRACT(MONTH FROM "timestamp") AS BIGINT)"#,
+			),
+			DbBackend::Sqlite => (
+				r#"CAST(strftime('%Y', "timestamp") AS INTEGER)"#,
+				r#"CAST(strftime('%m', "timestamp") AS INTEGER)"#,
+			),
+		};
+		let months: Vec<(i64, i64)> = MessageEntity::find()
+			.select_only()
+			.column_as(Expr::cust(year), MonthQueryAs::Year)
+			.column_as(Expr::cust(month), MonthQueryAs::Month)
+			.filter(channel_filter(&path)?)
+			.filter(user_filter(&path)?)
+			.group_by(Expr::cust(year))
+			.group_by(Expr::cust(month))
+			.order_by_desc(Expr::cust(year))
+			.order_by_desc(Expr::cust(month))
+			.into_values::<_, MonthQueryAs>()
+			.all(&db)
+			.await?;
+		months
+			.into_iter()
+			.map(|(year, month)| json!({ "year": year.to_string(), "month": month.to_string() }))
+			.collect::<Vec<_>>()
+	} else {
+		// A channel is logged continuously, so every day between the first and
+		// the last message is available
+		let first = MessageEntity::find()
+			.filter(channel_filter(&path)?)
+			.order_by_asc(MessageColumn::Timestamp)
+			.one(&db)
+			.await?;
+		let last = MessageEntity::find()
+			.filter(channel_filter(&path)?)
+			.order_by_desc(MessageColumn::Timestamp)
+			.one(&db)
+			.await?;
+		let mut days = Vec::new();
+		if let (Some(first), Some(last)) = (first, last) {
+			let mut day = last.timestamp.date();
+			while day >= first.timestamp.date() {
+				days.push(json!({
+					"year": day.year().to_string(),
+					"month": (day.month() as u8).to_string(),
+					"day": day.day().to_string(),
+				}));
+				day = match day.previous_day() {
+					Some(day) => day,
+					None => break,
+				};
+			}
+		}
+		days
+	};
+
+	if available_logs.is_empty() {
+		return Ok((StatusCode::NOT_FOUND, "could not load logs").into_response());
+	}
+	Ok(Json(json!({ "availableLogs": available_logs })).into_response())
+}
+
+pub fn router(state: AppState) -> Router<AppState> {
+	let mut router = Router::with_state(state)
+		.route("/channels", get(channels))
+		.route("/list", get(list));
+	for channel in ["/channel/:channel", "/channelid/:channelid"] {
+		router = router
+			.route(channel, get(latest_channel_logs))
+			.route(&format!("{}/:year/:month/:day", channel), get(channel_logs));
+		for user in ["user/:user", "userid/:userid"] {
+			router = router
+				.route(&format!("{}/{}", channel, user), get(latest_user_logs))
+				.route(
+					&format!("{}/{}/:year/:month", channel, user),
+					get(user_logs),
+				);
+		}
+	}
+	router
+}
@@ -0,0 +1,241 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::server::AppState;
+use async_stream::stream;
+use axum::{
+	extract::{
+		ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+		Path, State,
+	},
+	response::{
+		sse::{Event, KeepAlive, Sse},
+		IntoResponse, Response,
+	},
+};
+use axum_extra::extract::Query;
+use entity::messages::Model as Message;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use time::PrimitiveDateTime;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// How many events can be buffered for a slow live client before it starts
+/// missing them
+pub const LIVE_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LiveEvent {
+	/// A new chat message
+	Message(Message),
+	/// A single message was deleted by a moderator
+	Deletion {
+		channel: String,
+		id: Uuid,
+		username: Option<String>,
+		message: String,
+		#[serde(rename = "deleted-at")]
+		deleted_at: PrimitiveDateTime,
+	},
+	/// A user was timed out or banned, or the whole chat was cleared if there
+	/// is no user
+	Clear {
+		channel: String,
+		#[serde(rename = "user-id")]
+		user_id: Option<i64>,
+		username: Option<String>,
+		/// The length of the timeout in seconds, or none for a permanent ban
+		duration: Option<u64>,
+		timestamp: PrimitiveDateTime,
+	},
+}
+
+impl LiveEvent {
+	fn channel(&self) -> &str {
+		match self {
+			Self::Message(message) => &message.channel,
+			Self::Deletion { channel, .. } | Self::Clear { channel, .. } => channel,
+		}
+	}
+
+	fn username(&self) -> Option<&str> {
+		match self {
+			Self::Message(message) => Some(&message.username),
+			Self::Deletion { username, .. } | Self::Clear { username, .. } => username.as_deref(),
+		}
+	}
+
+	fn text(&self) -> Option<&str> {
+		match self {
+			Self::Message(message) => Some(&message.message),
+			Self::Deletion { message, .. } => Some(message),
+			Self::Clear { .. } => None,
+		}
+	}
+
+	fn event_name(&self) -> &'static str {
+		match self {
+			Self::Message(_) => "message",
+			Self::Deletion { .. } => "deletion",
+			Self::Clear { .. } => "clear",
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct LiveParams {
+	#[serde(
+		default,
+		alias = "user",
+		alias = "username",
+		alias = "usernames",
+		alias = "name",
+		alias = "names"
+	)]
+	users: Vec<String>,
+	#[serde(default, alias = "keyword")]
+	keywords: Vec<String>,
+}
+
+struct LiveFilter {
+	channel: String,
+	users: Vec<String>,
+	keywords: Vec<String>,
+}
+
+impl LiveFilter {
+	fn new(channel: String, params: LiveParams) -> Self {
+		Self {
+			channel: channel.to_lowercase(),
+			users: params
+				.users
+				.iter()
+				.map(|user| user.to_lowercase())
+				.collect(),
+			keywords: params
+				.keywords
+				.iter()
+				.map(|keyword| keyword.to_lowercase())
+				.collect(),
+		}
+	}
+
+	/// Every filter that was given must match the event. Events without a
+	/// user or text will never match a user or keyword filter.
+	fn matches(&self, event: &LiveEvent) -> bool {
+		if event.channel() != self.channel {
+			return false;
+		}
+		if !self.users.is_empty()
+			&& !event
+				.username()
+				.map(|username| {
+					self.users
+						.iter()
+						.any(|user| username.eq_ignore_ascii_case(user))
+				})
+				.unwrap_or(false)
+		{
+			return false;
+		}
+		if !self.keywords.is_empty()
+			&& !event
+				.text()
+				.map(|text| {
+					let text = text.to_lowercase();
+					self.keywords.iter().any(|keyword| text.contains(keyword))
+				})
+				.unwrap_or(false)
+		{
+			return false;
+		}
+		true
+	}
+}
+
+fn filtered_events(
+	mut rx: broadcast::Receiver<LiveEvent>,
+	filter: LiveFilter,
+) -> impl Stream<Item = LiveEvent> {
+	stream! {
+		loop {
+			match rx.recv().await {
+				Ok(event) => {
+					if filter.matches(&event) {
+						yield event;
+					}
+				}
+				Err(RecvError::Lagged(skipped)) => {
+					warn!("live client for #{} lagged behind, skipped {} events", filter.channel, skipped);
+				}
+				Err(RecvError::Closed) => break,
+			}
+		}
+	}
+}
+
+async fn websocket_tail(
+	mut socket: WebSocket,
+	rx: broadcast::Receiver<LiveEvent>,
+	filter: LiveFilter,
+) {
+	let events = filtered_events(rx, filter);
+	futures_util::pin_mut!(events);
+	loop {
+		tokio::select! {
+			event = futures_util::StreamExt::next(&mut events) => {
+				let event = match event {
+					Some(event) => event,
+					None => break,
+				};
+				let json = serde_json::to_string(&event).expect("failed to serialize live event");
+				if socket.send(WsMessage::Text(json)).await.is_err() {
+					break;
+				}
+			}
+			msg = socket.recv() => match msg {
+				Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+				Some(Ok(_)) => continue,
+			}
+		}
+	}
+}
+
+/// Streams live events for a channel, as JSON over a WebSocket if the client
+/// asks for an upgrade, or as Server-Sent Events otherwise.
+pub async fn live(
+	State(state): State<AppState>,
+	Path(channel): Path<String>,
+	Query(params): Query<LiveParams>,
+	ws: Option<WebSocketUpgrade>,
+) -> Response {
+	let filter = LiveFilter::new(channel, params);
+	let rx = state.live_tx.subscribe();
+	match ws {
+		Some(ws) => ws
+			.on_upgrade(move |socket| websocket_tail(socket, rx, filter))
+			.into_response(),
+		None => {
+			let events = filtered_events(rx, filter);
+			let sse_events = stream! {
+				for await event in events {
+					yield Ok::<_, Infallible>(
+						Event::default()
+							.event(event.event_name())
+							.json_data(&event)
+							.expect("failed to serialize live event"),
+					);
+				}
+			};
+			Sse::new(sse_events)
+				.keep_alive(KeepAlive::default())
+				.into_response()
+		}
+	}
+}
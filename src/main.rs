@@ -7,14 +7,35 @@
 #[macro_use]
 extern crate log;
 
+pub mod archive;
+pub mod badges;
+pub mod cli;
 pub mod config;
+pub mod emotes;
 pub mod error;
+pub mod export;
+pub mod import;
+pub mod justlog;
+pub mod live;
+pub mod manifest;
+pub mod migrate;
+pub mod parquet_rollup;
+pub mod partitions;
 pub mod process;
+pub mod recent_messages;
+pub mod reconstruct;
+pub mod reprocess;
+pub mod retention;
 pub mod rollup;
+pub mod search;
 pub mod server;
+pub mod streams;
+pub mod timezone;
 pub mod token;
+pub mod upload;
 
 use async_signals::Signals;
+use clap::Parser;
 use color_eyre::eyre::{Result, WrapErr};
 use futures_util::StreamExt;
 use irc::{
@@ -22,44 +43,119 @@ use irc::{
 	proto::Command,
 };
 use migration::{Migrator, MigratorTrait};
-use sea_orm::{ConnectOptions, Database};
+use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
+/// Connects to a database, without touching its migrations
+pub async fn open_database(url: &str) -> Result<DatabaseConnection> {
+	let mut sql_config = ConnectOptions::new(url.to_string());
+	sql_config
+		.sqlx_logging(true)
+		.sqlx_logging_level(log::LevelFilter::Trace);
+	Ok(Database::connect(sql_config).await?)
+}
+
+/// Connects to a database and brings it up to date
+pub async fn connect_database(url: &str) -> Result<DatabaseConnection> {
+	let db = open_database(url).await?;
+	Migrator::up(&db, None).await?;
+	Ok(db)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
 	color_eyre::install()?;
 	pretty_env_logger::init();
 
-	let config = Arc::new(
-		ron::from_str::<config::Config>(
-			&tokio::fs::read_to_string("config.ron")
-				.await
-				.wrap_err("failed to read config.ron")?,
-		)
-		.wrap_err("failed to parse config.ron")?,
-	);
+	let cli = cli::Cli::parse();
+
+	let config = Arc::new(config::Config::read(&cli.config).await?);
+
+	match cli.command {
+		None | Some(cli::Command::Run) => run(config).await,
+		Some(cli::Command::Migrate { command }) => migrate::migrate(&config, command).await,
+		Some(cli::Command::Rollup {
+			date,
+			from,
+			to,
+			missing,
+			force,
+		}) => {
+			let yesterday = OffsetDateTime::now_utc()
+				.date()
+				.previous_day()
+				.expect("no day before today");
+			let db = connect_database(&config.database).await?;
+			if missing {
+				rollup::backfill_rollups(&db, &config).await
+			} else {
+				let from = from.or(date).unwrap_or(yesterday);
+				let to = to.or(date).unwrap_or(yesterday);
+				rollup::rollup_range(&db, &config, from, to, force).await
+			}
+		}
+		Some(cli::Command::VerifyRollups) => manifest::verify_rollups(&config).await,
+		Some(cli::Command::Prune { dry_run }) => {
+			let db = connect_database(&config.database).await?;
+			retention::prune(&db, &config, dry_run || config.retention_dry_run).await
+		}
+		Some(cli::Command::Search {
+			channel,
+			users,
+			start,
+			end,
+			format,
+		}) => search::search(&config, channel, users, start, end, format).await,
+		Some(cli::Command::Export {
+			channel,
+			start,
+			end,
+			output,
+		}) => export::export(&config, channel, start, end, output).await,
+		Some(cli::Command::CheckConfig) => config::check_config(&config).await,
+		Some(cli::Command::Partition { undo }) => {
+			partitions::partition_messages(&config, undo).await
+		}
+		Some(cli::Command::Reprocess { files, database }) => {
+			reprocess::reprocess(&config, files, database).await
+		}
+		Some(cli::Command::ImportRollups { paths }) => import::import_rollups(&config, paths).await,
+		Some(cli::Command::Import {
+			format,
+			paths,
+			utc_offset,
+		}) => import::import(&config, format, paths, utc_offset).await,
+	}
+}
 
+async fn run(config: Arc<config::Config>) -> Result<()> {
 	let token = token::get_token(&config.twitch)
 		.await
 		.wrap_err("failed to get twitch token to log in with")?;
 
-	let mut sql_config = ConnectOptions::new(config.database.clone());
-	sql_config
-		.sqlx_logging(true)
-		.sqlx_logging_level(log::LevelFilter::Trace);
-	let db = Database::connect(sql_config).await?;
-	Migrator::up(&db, None).await?;
+	let db = connect_database(&config.database).await?;
+	let partitioned = partitions::is_partitioned(&db).await?;
 
 	let parent_cancel_token = CancellationToken::new();
 	let cancel_token = parent_cancel_token.child_token();
 
 	let (rollup_tx, rollup_rx) = mpsc::unbounded_channel();
 	tokio::spawn(rollup::rollup_task(db.clone(), config.clone(), rollup_rx));
+	if !config.retention.is_empty() {
+		tokio::spawn(retention::retention_task(db.clone(), config.clone()));
+	}
+	if config.partitioning.is_some() {
+		tokio::spawn(partitions::partition_task(db.clone(), config.clone()));
+	}
+	if !config.stream_channels().is_empty() {
+		tokio::spawn(streams::stream_task(db.clone(), config.clone()));
+	}
 
 	let mut signals = Signals::new(vec![libc::SIGUSR1, libc::SIGTERM])
 		.wrap_err("failed to attach to SIGTERM and SIGUSR1")?;
@@ -76,10 +172,13 @@ async fn main() -> Result<()> {
 		}
 	});
 
+	let (live_tx, _) = broadcast::channel(live::LIVE_EVENT_CAPACITY);
+
 	if config.port != 0 {
 		tokio::spawn(server::run_server(
 			config.clone(),
 			db.clone(),
+			live_tx.clone(),
 			cancel_token.child_token(),
 		));
 	}
@@ -120,7 +219,7 @@ async fn main() -> Result<()> {
 		.stream()
 		.wrap_err("failed to get stream of Twitch IRC")?;
 
-	let message_tx = process::spawn_message_processor(db);
+	let message_tx = process::spawn_message_processor(db, live_tx, partitioned);
 
 	loop {
 		while let Some(message) = tokio::select! {
@@ -132,8 +231,12 @@ async fn main() -> Result<()> {
 				return Ok(());
 			}
 		} {
+			let received_at = OffsetDateTime::now_utc();
 			message_tx
-				.send(message)
+				.send((
+					message,
+					PrimitiveDateTime::new(received_at.date(), received_at.time()),
+				))
 				.wrap_err("failed to send message")?;
 		}
 	}
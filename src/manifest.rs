@@ -0,0 +1,246 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The rollup manifest, which records every finished rollup file so archives
+//! can be verified, and days that are already rolled up can be skipped.
+
+use crate::{
+	config::{Config, RollupFormat},
+	rollup::{self, parse_log_file_name, RollupPeriod},
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+};
+use time::Date;
+use tokio::{io::AsyncReadExt, sync::Mutex};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Held while the manifest is read, changed and written back, so rollups and
+/// uploads happening at once don't drop each other's changes
+static MANIFEST_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+	/// Every finished rollup, by its path relative to the rollup directory
+	pub rollups: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+	pub channel: String,
+	pub date: Date,
+	#[serde(default)]
+	pub period: RollupPeriod,
+	pub format: RollupFormat,
+	pub messages: usize,
+	pub bytes: u64,
+	pub sha256: String,
+	/// Whether it's been uploaded to object storage
+	#[serde(default)]
+	pub uploaded: bool,
+}
+
+impl ManifestEntry {
+	/// Checks whether a rollup has a local day's messages, not counting
+	/// streams
+	pub fn covers(&self, date: Date) -> bool {
+		match self.period {
+			RollupPeriod::Stream(_) => false,
+			RollupPeriod::Month => self.date == rollup::month_start(date),
+			_ => self.date == date,
+		}
+	}
+}
+
+/// Gets the path a file is written to before it's finished, so a crash never
+/// leaves a truncated file that looks complete.
+pub fn temp_path(path: &Path) -> PathBuf {
+	let mut temp_path = path.as_os_str().to_owned();
+	temp_path.push(".tmp");
+	PathBuf::from(temp_path)
+}
+
+/// Hashes a file with SHA-256, returning the hash in hex and the file's size
+async fn hash_file(path: &Path) -> Result<(String, u64)> {
+	let mut file = tokio::fs::File::open(path)
+		.await
+		.wrap_err_with(|| format!("failed to open {}", path.display()))?;
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0; 64 * 1024];
+	let mut bytes = 0;
+	loop {
+		let read = file
+			.read(&mut buf)
+			.await
+			.wrap_err_with(|| format!("failed to read {}", path.display()))?;
+		if read == 0 {
+			break;
+		}
+		hasher.update(&buf[..read]);
+		bytes += read as u64;
+	}
+	let sha256 = hasher
+		.finalize()
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect();
+	Ok((sha256, bytes))
+}
+
+impl Manifest {
+	fn path(config: &Config) -> PathBuf {
+		config.rollup_dir.join(MANIFEST_FILE_NAME)
+	}
+
+	/// Reads the manifest, which is empty if nothing has been rolled up yet
+	pub async fn read(config: &Config) -> Result<Self> {
+		let path = Self::path(config);
+		if !path.exists() {
+			return Ok(Self::default());
+		}
+		serde_json::from_str(
+			&tokio::fs::read_to_string(&path)
+				.await
+				.wrap_err("failed to read rollup manifest")?,
+		)
+		.wrap_err("failed to parse rollup manifest")
+	}
+
+	/// Writes the manifest, replacing the old one all at once
+	async fn write(&self, config: &Config) -> Result<()> {
+		let path = Self::path(config);
+		let temp_path = temp_path(&path);
+		let json =
+			serde_json::to_vec_pretty(self).wrap_err("failed to serialize rollup manifest")?;
+		tokio::fs::write(&temp_path, json)
+			.await
+			.wrap_err("failed to write rollup manifest")?;
+		tokio::fs::rename(&temp_path, &path)
+			.await
+			.wrap_err("failed to replace rollup manifest")
+	}
+
+	/// Changes the manifest as it is on disk, and writes it back
+	pub async fn update(config: &Config, change: impl FnOnce(&mut Self)) -> Result<()> {
+		let _lock = MANIFEST_LOCK.lock().await;
+		let mut manifest = Self::read(config).await?;
+		change(&mut manifest);
+		manifest.write(config).await
+	}
+
+	/// Moves a finished rollup from its temporary path to where it belongs, and
+	/// records it, going by its name for what it holds.
+	pub async fn finish_rollup(config: &Config, path: &Path, messages: usize) -> Result<()> {
+		let name = path
+			.file_name()
+			.and_then(|file_name| file_name.to_str())
+			.and_then(parse_log_file_name)
+			.ok_or_else(|| eyre!("{} isn't named like a rollup", path.display()))?;
+		tokio::fs::rename(temp_path(path), path)
+			.await
+			.wrap_err_with(|| format!("failed to move {} into place", path.display()))?;
+		let (sha256, bytes) = hash_file(path).await?;
+		let relative_path = path
+			.strip_prefix(&config.rollup_dir)
+			.unwrap_or(path)
+			.to_string_lossy()
+			.into_owned();
+		let entry = ManifestEntry {
+			channel: name.channel,
+			date: name.date,
+			period: name.period,
+			format: name.format,
+			messages,
+			bytes,
+			sha256,
+			uploaded: false,
+		};
+		Self::update(config, |manifest| {
+			manifest.rollups.insert(relative_path, entry);
+		})
+		.await
+	}
+
+	/// Checks whether a channel's day has been rolled up in a format, by
+	/// itself or as part of its month, and the files are still there or were
+	/// uploaded. Streams aren't counted as part of a day.
+	pub fn is_rolled_up(
+		&self,
+		config: &Config,
+		channel: &str,
+		date: Date,
+		format: RollupFormat,
+	) -> bool {
+		self.rollups.iter().any(|(path, entry)| {
+			entry.channel == channel
+				&& entry.covers(date)
+				&& entry.format == format
+				&& (entry.uploaded || config.rollup_dir.join(path).exists())
+		})
+	}
+
+	/// Checks whether a stream has been rolled up in a format, and the file is
+	/// still there or was uploaded.
+	pub fn is_stream_rolled_up(&self, config: &Config, id: &str, format: RollupFormat) -> bool {
+		self.rollups.iter().any(|(path, entry)| {
+			entry.period == RollupPeriod::Stream(id.to_string())
+				&& entry.format == format
+				&& (entry.uploaded || config.rollup_dir.join(path).exists())
+		})
+	}
+}
+
+/// Checks that a rollup is still the size and hash it was written with, or
+/// that it was uploaded.
+pub async fn is_intact(config: &Config, path: &str, entry: &ManifestEntry) -> Result<bool> {
+	let full_path = config.rollup_dir.join(path);
+	if !full_path.exists() {
+		return Ok(entry.uploaded);
+	}
+	let (sha256, bytes) = hash_file(&full_path).await?;
+	Ok(bytes == entry.bytes && sha256 == entry.sha256)
+}
+
+/// Checks that every rollup in the manifest is still there, and has the same
+/// size and hash as when it was written. Rollups that were uploaded and then
+/// deleted are skipped.
+pub async fn verify_rollups(config: &Config) -> Result<()> {
+	let manifest = Manifest::read(config).await?;
+	let mut problems = 0_usize;
+	let mut uploaded_only = 0_usize;
+	for (path, entry) in &manifest.rollups {
+		let full_path = config.rollup_dir.join(path);
+		if !full_path.exists() && entry.uploaded {
+			uploaded_only += 1;
+			continue;
+		}
+		if !full_path.exists() {
+			println!("missing: {}", path);
+			problems += 1;
+			continue;
+		}
+		let (sha256, bytes) = hash_file(&full_path).await?;
+		if bytes != entry.bytes || sha256 != entry.sha256 {
+			println!("corrupt: {}", path);
+			problems += 1;
+		}
+	}
+	println!(
+		"Verified {} rollups, {} problems, {} skipped as they're only uploaded",
+		manifest.rollups.len() - uploaded_only,
+		problems,
+		uploaded_only
+	);
+	if problems > 0 {
+		return Err(eyre!("{} rollups are missing or corrupt", problems));
+	}
+	Ok(())
+}
@@ -0,0 +1,44 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{cli::MigrateCommand, config::Config, open_database};
+use color_eyre::eyre::{Result, WrapErr};
+use migration::{Migrator, MigratorTrait};
+
+/// Applies, rolls back, or shows the status of the database's migrations
+pub async fn migrate(config: &Config, command: MigrateCommand) -> Result<()> {
+	let db = open_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+	match command {
+		MigrateCommand::Up { steps } => Migrator::up(&db, steps)
+			.await
+			.wrap_err("failed to apply migrations"),
+		MigrateCommand::Down { steps } => Migrator::down(&db, Some(steps))
+			.await
+			.wrap_err("failed to roll back migrations"),
+		MigrateCommand::Status => {
+			Migrator::install(&db)
+				.await
+				.wrap_err("failed to create migrations table")?;
+			let applied = Migrator::get_migration_models(&db)
+				.await
+				.wrap_err("failed to get applied migrations")?;
+			for migration in Migrator::migrations() {
+				let status = if applied
+					.iter()
+					.any(|model| model.version == migration.name())
+				{
+					"applied"
+				} else {
+					"pending"
+				};
+				println!("{} {}", status, migration.name());
+			}
+			Ok(())
+		}
+	}
+}
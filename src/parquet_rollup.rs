@@ -0,0 +1,219 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parquet rollups, for loading chat history into tools like DuckDB and
+//! Spark. They're written into Hive-style partitions under `parquet/` in the
+//! rollup directory, like `parquet/channel=jerma985/date=2022-09-07/`, or
+//! `parquet/channel=jerma985/month=2022-09/` for a whole month. The channel
+//! is only in the partition key, rather than also being a column.
+
+use crate::{
+	config::{CompressionAlgorithm, Config, RollupFormat},
+	manifest::temp_path,
+	rollup::{get_log_file_name, RollupPeriod},
+};
+use arrow_array::{
+	ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use color_eyre::eyre::{Result, WrapErr};
+use entity::messages::Model as Message;
+use parquet::{
+	arrow::ArrowWriter,
+	basic::{Compression, GzipLevel, ZstdLevel},
+	file::properties::WriterProperties,
+};
+use std::{fs::File, path::PathBuf, sync::Arc};
+use time::{macros::format_description, Date, PrimitiveDateTime};
+
+/// Gets where the Parquet rollup of part of a channel's day, or a whole month,
+/// goes
+pub fn rollup_path(
+	config: &Config,
+	channel: &str,
+	date: Date,
+	period: &RollupPeriod,
+) -> Result<PathBuf> {
+	let partition = match period {
+		RollupPeriod::Month => format!(
+			"month={}",
+			date.format(format_description!("[year]-[month]"))?
+		),
+		_ => format!(
+			"date={}",
+			date.format(format_description!("[year]-[month]-[day]"))?
+		),
+	};
+	Ok(config
+		.rollup_dir
+		.join("parquet")
+		.join(format!("channel={}", channel))
+		.join(partition)
+		.join(get_log_file_name(
+			channel,
+			date,
+			period,
+			RollupFormat::Parquet,
+			None,
+		)?))
+}
+
+fn schema() -> SchemaRef {
+	let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+	Arc::new(Schema::new(vec![
+		Field::new("id", DataType::Utf8, false),
+		Field::new("room_id", DataType::Int64, false),
+		Field::new("user_id", DataType::Int64, false),
+		Field::new("username", DataType::Utf8, false),
+		Field::new("message", DataType::Utf8, false),
+		Field::new("timestamp", timestamp.clone(), false),
+		Field::new("deleted", DataType::Boolean, false),
+		Field::new("deleted_at", timestamp, true),
+		Field::new("replying_to", DataType::Utf8, true),
+		Field::new("subscriber", DataType::Boolean, false),
+		Field::new("moderator", DataType::Boolean, false),
+		Field::new("vip", DataType::Boolean, false),
+		Field::new("emotes", DataType::Utf8, true),
+		Field::new("badges", DataType::Utf8, true),
+		Field::new("user_type", DataType::Utf8, true),
+		Field::new("badge_info", DataType::Utf8, true),
+		Field::new("sub_months", DataType::Int64, true),
+		Field::new("bits_tier", DataType::Int64, true),
+	]))
+}
+
+fn timestamp_micros(timestamp: PrimitiveDateTime) -> i64 {
+	(timestamp.assume_utc().unix_timestamp_nanos() / 1_000) as i64
+}
+
+fn record_batch(schema: SchemaRef, messages: &[&Message]) -> Result<RecordBatch> {
+	let strings = |f: fn(&Message) -> Option<String>| -> ArrayRef {
+		Arc::new(
+			messages
+				.iter()
+				.map(|message| f(message))
+				.collect::<StringArray>(),
+		)
+	};
+	let integers = |f: fn(&Message) -> Option<i64>| -> ArrayRef {
+		Arc::new(
+			messages
+				.iter()
+				.map(|message| f(message))
+				.collect::<Int64Array>(),
+		)
+	};
+	let booleans = |f: fn(&Message) -> bool| -> ArrayRef {
+		Arc::new(
+			messages
+				.iter()
+				.map(|message| Some(f(message)))
+				.collect::<BooleanArray>(),
+		)
+	};
+	let timestamps = |f: fn(&Message) -> Option<PrimitiveDateTime>| -> ArrayRef {
+		Arc::new(
+			messages
+				.iter()
+				.map(|message| f(message).map(timestamp_micros))
+				.collect::<TimestampMicrosecondArray>()
+				.with_timezone("UTC"),
+		)
+	};
+	RecordBatch::try_new(schema, vec![
+		strings(|message| Some(message.id.to_string())),
+		integers(|message| Some(message.room_id)),
+		integers(|message| Some(message.user_id)),
+		strings(|message| Some(message.username.clone())),
+		strings(|message| Some(message.message.clone())),
+		timestamps(|message| Some(message.timestamp)),
+		booleans(|message| message.deleted),
+		timestamps(|message| message.deleted_at),
+		strings(|message| {
+			message
+				.replying_to
+				.map(|replying_to| replying_to.to_string())
+		}),
+		booleans(|message| message.subscriber),
+		booleans(|message| message.moderator),
+		booleans(|message| message.vip),
+		strings(|message| message.emotes.clone()),
+		strings(|message| message.badges.clone()),
+		strings(|message| message.user_type.clone()),
+		strings(|message| message.badge_info.clone()),
+		integers(|message| message.sub_months.map(i64::from)),
+		integers(|message| message.bits_tier.map(i64::from)),
+	])
+	.wrap_err("failed to build record batch")
+}
+
+/// A Parquet rollup of part of a channel's day, or a month, being written
+pub struct ParquetRollup {
+	path: PathBuf,
+	schema: SchemaRef,
+	writer: ArrowWriter<File>,
+}
+
+impl ParquetRollup {
+	pub fn create(
+		config: &Config,
+		channel: &str,
+		date: Date,
+		period: &RollupPeriod,
+	) -> Result<Self> {
+		let path = rollup_path(config, channel, date, period)?;
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)
+				.wrap_err_with(|| format!("failed to create {}", parent.display()))?;
+		}
+		let file = File::create(temp_path(&path))
+			.wrap_err_with(|| format!("failed to create {}", path.display()))?;
+		// Parquet compresses each column itself, rather than the whole file
+		let compression = match config.rollup_compression {
+			Some(compression) => match compression.algorithm {
+				CompressionAlgorithm::Gzip => Compression::GZIP(match compression.level {
+					Some(level) => GzipLevel::try_new(level)?,
+					None => GzipLevel::default(),
+				}),
+				CompressionAlgorithm::Zstd => Compression::ZSTD(match compression.level {
+					Some(level) => ZstdLevel::try_new(level as i32)?,
+					None => ZstdLevel::default(),
+				}),
+			},
+			None => Compression::UNCOMPRESSED,
+		};
+		let properties = WriterProperties::builder()
+			.set_compression(compression)
+			.build();
+		let schema = schema();
+		let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))
+			.wrap_err("failed to start Parquet file")?;
+		Ok(Self {
+			path,
+			schema,
+			writer,
+		})
+	}
+
+	pub fn write(&mut self, messages: &[&Message]) -> Result<()> {
+		let batch = record_batch(self.schema.clone(), messages)?;
+		self.writer
+			.write(&batch)
+			.wrap_err("failed to write to Parquet file")
+	}
+
+	/// Writes the Parquet footer, and makes sure everything is on disk. The
+	/// file is still at its temporary path, the returned path is where it
+	/// belongs.
+	pub fn finish(self) -> Result<PathBuf> {
+		self.writer
+			.into_inner()
+			.wrap_err("failed to finish Parquet file")?
+			.sync_all()
+			.wrap_err("failed to sync Parquet file")?;
+		Ok(self.path)
+	}
+}
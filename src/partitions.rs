@@ -0,0 +1,351 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Keeps messages partitioned by month on Postgres, creating partitions for
+//! the months ahead and detaching old ones so they can be archived.
+
+use crate::{
+	config::{Config, PartitionConfig},
+	manifest::Manifest,
+	open_database, retention, timezone,
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement, TransactionTrait};
+use std::{sync::Arc, time::Duration};
+use time::{Date, Month, OffsetDateTime};
+
+/// How often to check the partitions
+const PARTITION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Counts the months since year 0, so months can be added and compared
+fn month_index(date: Date) -> i32 {
+	date.year() * 12 + i32::from(u8::from(date.month())) - 1
+}
+
+fn month_date(index: i32) -> Date {
+	let month = Month::try_from((index % 12 + 1) as u8).expect("month out of range");
+	Date::from_calendar_date(index / 12, month, 1).expect("year out of range")
+}
+
+fn month_start(index: i32) -> String {
+	format!("{:04}-{:02}-01", index / 12, index % 12 + 1)
+}
+
+fn partition_name(index: i32) -> String {
+	format!("messages-{:04}-{:02}", index / 12, index % 12 + 1)
+}
+
+/// Gets the month a partition is for from its name, which isn't one for the
+/// default partition
+fn parse_partition_name(name: &str) -> Option<i32> {
+	let (year, month) = name.strip_prefix("messages-")?.split_once('-')?;
+	let (year, month) = (year.parse::<i32>().ok()?, month.parse::<i32>().ok()?);
+	(1..=12).contains(&month).then_some(year * 12 + month - 1)
+}
+
+async fn execute(db: &impl ConnectionTrait, sql: String) -> Result<()> {
+	db.execute(Statement::from_string(DbBackend::Postgres, sql))
+		.await
+		.map(|_| ())
+		.wrap_err("failed to change partitions")
+}
+
+/// Checks whether `messages` is a partitioned table, which only Postgres has
+pub async fn is_partitioned(db: &impl ConnectionTrait) -> Result<bool> {
+	if db.get_database_backend() != DbBackend::Postgres {
+		return Ok(false);
+	}
+	db.query_one(Statement::from_string(
+		DbBackend::Postgres,
+		r#"SELECT EXISTS (
+			SELECT 1 FROM pg_partitioned_table
+			JOIN pg_class ON pg_class.oid = pg_partitioned_table.partrelid
+			WHERE pg_class.relname = 'messages' AND pg_table_is_visible(pg_class.oid)
+		) AS "partitioned""#
+			.to_string(),
+	))
+	.await
+	.wrap_err("failed to check if messages are partitioned")?
+	.ok_or_else(|| eyre!("no result checking if messages are partitioned"))?
+	.try_get("", "partitioned")
+	.wrap_err("failed to check if messages are partitioned")
+}
+
+/// Replaces `messages` with a new table, made by the `create` statements as
+/// `messages-replacement`, keeping its rows and indexes, but with a new primary
+/// key. Only one message is kept for each ID, preferring deleted ones, then
+/// the earliest.
+async fn replace_messages(
+	db: &impl ConnectionTrait,
+	create: Vec<String>,
+	primary_key: &str,
+) -> Result<()> {
+	let indexes = db
+		.query_all(Statement::from_string(
+			DbBackend::Postgres,
+			r#"SELECT pg_get_indexdef(pg_index.indexrelid) AS "definition" FROM pg_index
+			JOIN pg_class ON pg_class.oid = pg_index.indrelid
+			WHERE pg_class.relname = 'messages' AND pg_table_is_visible(pg_class.oid)
+			AND NOT pg_index.indisprimary"#
+				.to_string(),
+		))
+		.await
+		.wrap_err("failed to get indexes")?
+		.into_iter()
+		.map(|index| index.try_get::<String>("", "definition"))
+		.collect::<Result<Vec<_>, _>>()
+		.wrap_err("failed to get index definition")?;
+	for statement in create {
+		execute(db, statement).await?;
+	}
+	execute(
+		db,
+		r#"INSERT INTO "messages-replacement" SELECT DISTINCT ON (id) * FROM messages
+		ORDER BY id, deleted DESC, "timestamp""#
+			.to_string(),
+	)
+	.await?;
+	execute(db, "DROP TABLE messages".to_string()).await?;
+	execute(
+		db,
+		r#"ALTER TABLE "messages-replacement" RENAME TO messages"#.to_string(),
+	)
+	.await?;
+	execute(
+		db,
+		format!("ALTER TABLE messages ADD PRIMARY KEY ({})", primary_key),
+	)
+	.await?;
+	for index in indexes {
+		// Indexes of a partitioned table are only on it, rather than on its
+		// partitions
+		execute(db, index.replacen(" ON ONLY ", " ON ", 1)).await?;
+	}
+	Ok(())
+}
+
+/// Moves every message into a new table partitioned by month, with a
+/// partition for each month from the oldest message up to now, and a default
+/// partition for anything outside of them.
+async fn partition(db: &impl ConnectionTrait) -> Result<()> {
+	let months = db
+		.query_all(Statement::from_string(
+			DbBackend::Postgres,
+			r#"SELECT
+				to_char(month, 'YYYY-MM') AS "name",
+				to_char(month, 'YYYY-MM-DD') AS "start",
+				to_char(month + interval '1 month', 'YYYY-MM-DD') AS "end"
+			FROM generate_series(
+				date_trunc('month', coalesce(
+					(SELECT min("timestamp") FROM messages),
+					now() AT TIME ZONE 'UTC'
+				)),
+				date_trunc('month', greatest(
+					(SELECT max("timestamp") FROM messages),
+					now() AT TIME ZONE 'UTC'
+				)),
+				interval '1 month'
+			) AS month"#
+				.to_string(),
+		))
+		.await
+		.wrap_err("failed to get months")?;
+	let mut create = vec![
+		r#"CREATE TABLE "messages-replacement" (LIKE messages INCLUDING DEFAULTS)
+		PARTITION BY RANGE ("timestamp")"#
+			.to_string(),
+		r#"CREATE TABLE "messages-default" PARTITION OF "messages-replacement" DEFAULT"#
+			.to_string(),
+	];
+	for month in months {
+		let name: String = month.try_get("", "name")?;
+		let start: String = month.try_get("", "start")?;
+		let end: String = month.try_get("", "end")?;
+		create.push(format!(
+			r#"CREATE TABLE "messages-{}" PARTITION OF "messages-replacement" FOR VALUES FROM ('{}') TO ('{}')"#,
+			name, start, end
+		));
+	}
+	// Postgres can only enforce uniqueness within a partition, so the
+	// partition key has to be part of the primary key
+	replace_messages(db, create, r#"id, "timestamp""#).await
+}
+
+/// Moves every message in attached partitions back into a plain table
+async fn unpartition(db: &impl ConnectionTrait) -> Result<()> {
+	replace_messages(
+		db,
+		vec![
+			r#"CREATE TABLE "messages-replacement" (LIKE messages INCLUDING DEFAULTS)"#.to_string(),
+		],
+		"id",
+	)
+	.await
+}
+
+/// Partitions messages by month, or with `undo`, moves them back into a plain
+/// table, all at once. Detached partitions are left alone.
+pub async fn partition_messages(config: &Config, undo: bool) -> Result<()> {
+	let db = open_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+	if db.get_database_backend() != DbBackend::Postgres {
+		return Err(eyre!("Messages can only be partitioned in Postgres"));
+	}
+	Migrator::up(&db, None)
+		.await
+		.wrap_err("failed to apply migrations")?;
+	if is_partitioned(&db).await? != undo {
+		info!(
+			"Messages are already {}",
+			if undo { "unpartitioned" } else { "partitioned" }
+		);
+		return Ok(());
+	}
+	let transaction = db.begin().await.wrap_err("failed to start transaction")?;
+	if undo {
+		unpartition(&transaction).await?;
+	} else {
+		partition(&transaction).await?;
+	}
+	transaction
+		.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(
+		"Messages are now {}",
+		if undo { "unpartitioned" } else { "partitioned" }
+	);
+	Ok(())
+}
+
+/// Checks that every local day overlapping a partition's month is rolled up
+/// and intact for each channel in it, as detached messages can't be searched
+/// or rolled up anymore.
+async fn is_month_archived(
+	db: &DatabaseConnection,
+	config: &Config,
+	manifest: &Manifest,
+	name: &str,
+	month: i32,
+) -> Result<bool> {
+	let channels = db
+		.query_all(Statement::from_string(
+			DbBackend::Postgres,
+			format!(r#"SELECT DISTINCT channel FROM "{}""#, name),
+		))
+		.await
+		.wrap_err("failed to get channels")?;
+	let (start, end) = (
+		month_date(month).midnight(),
+		month_date(month + 1).midnight() - time::Duration::nanoseconds(1),
+	);
+	for channel in channels {
+		let channel: String = channel
+			.try_get("", "channel")
+			.wrap_err("failed to get channel")?;
+		let zone = config.channel_timezone(&channel);
+		let mut date = timezone::to_local(start, zone).date();
+		while date <= timezone::to_local(end, zone).date() {
+			if !retention::is_day_archived(db, config, manifest, &channel, date).await? {
+				return Ok(false);
+			}
+			date = date.next_day().expect("ran out of days");
+		}
+	}
+	Ok(true)
+}
+
+/// Creates the partitions of this month and the months ahead, and detaches
+/// the partitions of months that are old enough, once they're archived.
+pub async fn maintain_partitions(
+	db: &DatabaseConnection,
+	config: &Config,
+	partitioning: &PartitionConfig,
+) -> Result<()> {
+	if !is_partitioned(db).await? {
+		warn!(
+			"Messages aren't partitioned. Stop the logger and run `partition` to partition them."
+		);
+		return Ok(());
+	}
+	let this_month = month_index(OffsetDateTime::now_utc().date());
+	for month in this_month..=this_month + partitioning.months_ahead as i32 {
+		execute(
+			db,
+			format!(
+				r#"CREATE TABLE IF NOT EXISTS "{}" PARTITION OF messages FOR VALUES FROM ('{}') TO ('{}')"#,
+				partition_name(month),
+				month_start(month),
+				month_start(month + 1)
+			),
+		)
+		.await?;
+	}
+
+	let detach_after = match partitioning.detach_after_months {
+		Some(detach_after) => detach_after as i32,
+		None => return Ok(()),
+	};
+	let partitions = db
+		.query_all(Statement::from_string(
+			DbBackend::Postgres,
+			r#"SELECT child.relname AS "name" FROM pg_inherits
+			JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+			JOIN pg_class parent ON parent.oid = pg_inherits.inhparent
+			WHERE parent.relname = 'messages' AND pg_table_is_visible(parent.oid)"#
+				.to_string(),
+		))
+		.await
+		.wrap_err("failed to get partitions")?;
+	let manifest = Manifest::read(config).await?;
+	let mut held_back = Vec::new();
+	for partition in partitions {
+		let name: String = partition
+			.try_get("", "name")
+			.wrap_err("failed to get partition name")?;
+		match parse_partition_name(&name) {
+			Some(month) if month + 1 + detach_after <= this_month => {
+				if !is_month_archived(db, config, &manifest, &name, month).await? {
+					held_back.push(name);
+					continue;
+				}
+				execute(
+					db,
+					format!(r#"ALTER TABLE messages DETACH PARTITION "{}""#, name),
+				)
+				.await?;
+				info!("Detached partition {} to be archived", name);
+			}
+			_ => {}
+		}
+	}
+	if !held_back.is_empty() {
+		warn!(
+			"Keeping {} attached, as not every day in them is rolled up and intact",
+			held_back.join(", ")
+		);
+	}
+	Ok(())
+}
+
+pub async fn partition_task(db: DatabaseConnection, config: Arc<Config>) {
+	let partitioning = match &config.partitioning {
+		Some(partitioning) => partitioning,
+		None => return,
+	};
+	if db.get_database_backend() != DbBackend::Postgres {
+		warn!("Messages can only be partitioned in Postgres");
+		return;
+	}
+	loop {
+		if let Err(error) = maintain_partitions(&db, &config, partitioning).await {
+			error!("Maintaining partitions failed: {:?}", error);
+		}
+		tokio::time::sleep(PARTITION_INTERVAL).await;
+	}
+}
@@ -4,53 +4,123 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use entity::messages::{ActiveModel as MessageActiveModel, Entity as MessageEntity};
+use crate::{badges::Badges, emotes, live::LiveEvent};
+use entity::{
+	messages::{
+		ActiveModel as MessageActiveModel, Column as MessageColumn, Entity as MessageEntity,
+		Model as MessageModel,
+	},
+	raw_lines::ActiveModel as RawLineActiveModel,
+};
 use irc::proto::{message::Tag, Command, Message};
-use sea_orm::{prelude::*, ActiveValue::Set, DatabaseConnection, EntityTrait, Unchanged};
+use sea_orm::{
+	prelude::*,
+	sea_query::{Expr, OnConflict},
+	ActiveValue::Set,
+	DatabaseConnection, EntityTrait,
+};
 use std::collections::HashMap;
 use time::{Duration, OffsetDateTime, PrimitiveDateTime};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use uuid::Uuid;
 
-pub fn spawn_message_processor(db: DatabaseConnection) -> mpsc::UnboundedSender<Message> {
+pub fn spawn_message_processor(
+	db: DatabaseConnection,
+	live_tx: broadcast::Sender<LiveEvent>,
+	partitioned: bool,
+) -> mpsc::UnboundedSender<(Message, PrimitiveDateTime)> {
 	let (tx, rx) = mpsc::unbounded_channel();
-	tokio::spawn(message_processor(rx, db));
+	tokio::spawn(message_processor(rx, db, live_tx, partitioned));
 	tx
 }
 
-async fn message_processor(mut rx: mpsc::UnboundedReceiver<Message>, db: DatabaseConnection) {
-	while let Some(message) = rx.recv().await {
+async fn message_processor(
+	mut rx: mpsc::UnboundedReceiver<(Message, PrimitiveDateTime)>,
+	db: DatabaseConnection,
+	live_tx: broadcast::Sender<LiveEvent>,
+	partitioned: bool,
+) {
+	while let Some((message, received_at)) = rx.recv().await {
 		debug!("{:?}", message);
-		let tags = message
-			.tags
-			.clone()
-			.unwrap_or_default()
-			.into_iter()
-			.map(|Tag(key, value)| (key, value))
-			.collect::<std::collections::HashMap<_, _>>();
-		match &message.command {
-			Command::NOTICE(target, msg) => {
-				handle_notice(target, msg).await;
+		archive_raw_line(&db, &message, received_at).await;
+		process_message(&db, &live_tx, &message, partitioned).await;
+	}
+}
+
+/// Handles a single IRC message, whether it was just received or is being
+/// replayed from an archive. Handling the same message twice is harmless.
+/// `partitioned` is whether messages are in a partitioned table.
+pub async fn process_message(
+	db: &DatabaseConnection,
+	live_tx: &broadcast::Sender<LiveEvent>,
+	message: &Message,
+	partitioned: bool,
+) {
+	let tags = message
+		.tags
+		.clone()
+		.unwrap_or_default()
+		.into_iter()
+		.map(|Tag(key, value)| (key, value))
+		.collect::<std::collections::HashMap<_, _>>();
+	match &message.command {
+		Command::NOTICE(target, msg) => {
+			handle_notice(target, msg).await;
+		}
+		Command::PRIVMSG(channel, msg) => {
+			let username = match message.source_nickname() {
+				Some(username) => username,
+				None => return,
+			};
+			let channel = channel.strip_prefix('#').unwrap_or(channel.as_str());
+			handle_privmsg(db, live_tx, channel, username, msg, tags, partitioned).await;
+		}
+		Command::Raw(command, value) => match command.as_str() {
+			"CLEARMSG" => {
+				handle_clearmsg(db, live_tx, value, tags).await;
 			}
-			Command::PRIVMSG(channel, msg) => {
-				let username = match message.source_nickname() {
-					Some(username) => username,
-					None => continue,
-				};
-				let channel = channel.strip_prefix('#').unwrap_or(channel.as_str());
-				handle_privmsg(&db, channel, username, msg, tags).await;
+			"CLEARCHAT" => {
+				handle_clearchat(live_tx, value, tags).await;
 			}
-			Command::Raw(command, value) => match command.as_str() {
-				"CLEARMSG" => {
-					handle_clearmsg(&db, tags).await;
-				}
-				_ => {
-					debug!("Unhandled message: [{}] {:?}", command, value);
-					continue;
-				}
-			},
-			_ => continue,
-		}
+			_ => {
+				debug!("Unhandled message: [{}] {:?}", command, value);
+			}
+		},
+		_ => {}
+	}
+}
+
+/// Serializes a message back into a line, without the `\r\n` that ends it. Only
+/// that is stripped, as trailing whitespace can be part of the message.
+pub fn irc_line(message: &Message) -> String {
+	let line = message.to_string();
+	line.strip_suffix("\r\n").unwrap_or(&line).to_string()
+}
+
+/// Stores every message we get as-is, so that history can be reprocessed when
+/// we start keeping track of more of it.
+pub async fn archive_raw_line(
+	db: &DatabaseConnection,
+	message: &Message,
+	received_at: PrimitiveDateTime,
+) {
+	let command_line = String::from(&message.command);
+	let mut words = command_line.split(' ');
+	let command = words.next().unwrap_or_default().to_string();
+	let channel = words
+		.next()
+		.and_then(|target| target.strip_prefix('#'))
+		.map(|channel| channel.to_string());
+	let model = RawLineActiveModel {
+		received_at: Set(received_at),
+		channel: Set(channel),
+		command: Set(command),
+		line: Set(irc_line(message)),
+		..Default::default()
+	};
+	// Losing a line from the archive isn't worth stopping message processing
+	if let Err(err) = model.insert(db).await {
+		error!("Archiving a raw line failed: {:?}", err);
 	}
 }
 
@@ -66,12 +136,56 @@ async fn handle_notice(_target: &str, msg: &str) {
 	}
 }
 
+/// Inserts a message, or if it's already in the database, refreshes what we
+/// parsed out of it without undoing its deletion. IDs aren't unique in a
+/// partitioned table, where only the ID and timestamp together are, so there
+/// the message is looked up by its ID first.
+async fn save_message(
+	db: &DatabaseConnection,
+	model: MessageActiveModel,
+	partitioned: bool,
+) -> Result<MessageModel, DbErr> {
+	if partitioned {
+		let id = model.id.clone().unwrap();
+		return match MessageEntity::find_by_id(id).one(db).await? {
+			Some(_) => model.update(db).await,
+			None => MessageEntity::insert(model).exec_with_returning(db).await,
+		};
+	}
+	MessageEntity::insert(model)
+		.on_conflict(
+			OnConflict::column(MessageColumn::Id)
+				.update_columns([
+					MessageColumn::Channel,
+					MessageColumn::RoomId,
+					MessageColumn::UserId,
+					MessageColumn::Username,
+					MessageColumn::Message,
+					MessageColumn::ReplyingTo,
+					MessageColumn::Subscriber,
+					MessageColumn::Moderator,
+					MessageColumn::Vip,
+					MessageColumn::Emotes,
+					MessageColumn::Badges,
+					MessageColumn::UserType,
+					MessageColumn::BadgeInfo,
+					MessageColumn::SubMonths,
+					MessageColumn::BitsTier,
+				])
+				.to_owned(),
+		)
+		.exec_with_returning(db)
+		.await
+}
+
 async fn handle_privmsg(
 	db: &DatabaseConnection,
+	live_tx: &broadcast::Sender<LiveEvent>,
 	channel: &str,
 	username: &str,
 	msg: &str,
 	tags: HashMap<String, Option<String>>,
+	partitioned: bool,
 ) {
 	let id = match tags.get("id") {
 		Some(Some(id)) => Uuid::parse_str(id).unwrap(),
@@ -110,6 +224,7 @@ async fn handle_privmsg(
 		_ => None,
 	};
 	debug!("[#{}] {}: {}", channel, username, msg);
+	let badges = Badges::from_tags(&tags);
 	let model = MessageActiveModel {
 		id: Set(id),
 		channel: Set(channel.to_string()),
@@ -119,21 +234,43 @@ async fn handle_privmsg(
 		message: Set(msg.to_string()),
 		timestamp: Set(timestamp),
 		replying_to: Set(replying_to),
-		subscriber: Set(tags.contains_key("subscriber")),
-		moderator: Set(tags.contains_key("mod")),
-		vip: Set(tags.contains_key("vip")),
+		subscriber: Set(badges.subscriber),
+		moderator: Set(badges.moderator),
+		vip: Set(badges.vip),
 		emotes: Set(tags.get("emotes").cloned().flatten()),
 		badges: Set(tags.get("badges").cloned().flatten()),
 		user_type: Set(tags.get("user-type").cloned().flatten()),
+		badge_info: Set(tags.get("badge-info").cloned().flatten()),
+		sub_months: Set(badges.sub_months),
+		bits_tier: Set(badges.bits_tier),
 		..Default::default()
 	};
-	model
-		.insert(db)
+	let message = save_message(db, model, partitioned)
 		.await
 		.expect("failed to insert message into database");
+	emotes::save_emotes(
+		db,
+		&[message.id],
+		emotes::emote_rows(
+			message.id,
+			&message.channel,
+			message.timestamp,
+			message.emotes.as_deref().unwrap_or_default(),
+			&message.message,
+		),
+	)
+	.await
+	.expect("failed to insert emotes into database");
+	// Sending only fails when nobody is watching live, which is fine
+	let _ = live_tx.send(LiveEvent::Message(message));
 }
 
-async fn handle_clearmsg(db: &DatabaseConnection, tags: HashMap<String, Option<String>>) {
+async fn handle_clearmsg(
+	db: &DatabaseConnection,
+	live_tx: &broadcast::Sender<LiveEvent>,
+	value: &[String],
+	tags: HashMap<String, Option<String>>,
+) {
 	let timestamp = match tags.get("tmi-sent-ts") {
 		Some(Some(timestamp)) => {
 			let timestamp = timestamp.parse::<i64>().expect("failed to parse timestamp");
@@ -152,16 +289,66 @@ async fn handle_clearmsg(db: &DatabaseConnection, tags: HashMap<String, Option<S
 			return;
 		}
 	};
-	let model = MessageActiveModel {
-		id: Unchanged(target_msg_id),
-		deleted: Set(true),
-		deleted_at: Set(Some(timestamp)),
-		..Default::default()
-	};
 	error!("Message deleted: {}", target_msg_id);
-	MessageEntity::update(model)
+	// The message may have been sent before we started logging, so this can
+	// update nothing
+	MessageEntity::update_many()
+		.col_expr(MessageColumn::Deleted, Expr::value(true))
+		.col_expr(MessageColumn::DeletedAt, Expr::value(Some(timestamp)))
+		.filter(MessageColumn::Id.eq(target_msg_id))
 		.exec(db)
 		.await
 		.expect("failed to update message");
 	debug!("message {} was deleted", target_msg_id);
+	if let [channel, message, ..] = value {
+		let _ = live_tx.send(LiveEvent::Deletion {
+			channel: channel.strip_prefix('#').unwrap_or(channel).to_string(),
+			id: target_msg_id,
+			username: tags.get("login").cloned().flatten(),
+			message: message.clone(),
+			deleted_at: timestamp,
+		});
+	}
+}
+
+async fn handle_clearchat(
+	live_tx: &broadcast::Sender<LiveEvent>,
+	value: &[String],
+	tags: HashMap<String, Option<String>>,
+) {
+	let timestamp = match tags.get("tmi-sent-ts") {
+		Some(Some(timestamp)) => {
+			let timestamp = timestamp.parse::<i64>().expect("failed to parse timestamp");
+			let date_time = OffsetDateTime::UNIX_EPOCH + Duration::milliseconds(timestamp);
+			PrimitiveDateTime::new(date_time.date(), date_time.time())
+		}
+		_ => {
+			warn!("CLEARCHAT was missing timestamp tag");
+			return;
+		}
+	};
+	let channel = match value.first() {
+		Some(channel) => channel.strip_prefix('#').unwrap_or(channel),
+		None => {
+			warn!("CLEARCHAT was missing channel");
+			return;
+		}
+	};
+	let user_id = match tags.get("target-user-id") {
+		Some(Some(id)) => id.parse::<i64>().ok(),
+		_ => None,
+	};
+	let duration = match tags.get("ban-duration") {
+		Some(Some(duration)) => duration.parse::<u64>().ok(),
+		_ => None,
+	};
+	let username = value.get(1).cloned();
+	debug!("[#{}] chat cleared for {:?}", channel, username);
+	let _ = live_tx.send(LiveEvent::Clear {
+		channel: channel.to_string(),
+		user_id,
+		username,
+		duration,
+		timestamp,
+	});
 }
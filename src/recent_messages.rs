@@ -0,0 +1,139 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A [recent-messages](https://recent-messages.robotty.de/) compatible API,
+//! which chat clients like Chatterino use to load a channel's backlog when
+//! joining it.
+
+use crate::{error::Result, process, reconstruct};
+use axum::{
+	extract::{Path, State},
+	response::IntoResponse,
+	Json,
+};
+use axum_extra::extract::Query;
+use entity::{
+	messages::{Column as MessageColumn, Entity as MessageEntity},
+	raw_lines::{Column as RawLineColumn, Entity as RawLineEntity},
+};
+use irc::proto::{message::Tag, Message as IrcMessage};
+use sea_orm::{prelude::*, DatabaseConnection, EntityTrait, QueryOrder, QuerySelect};
+use serde::Deserialize;
+use serde_json::json;
+use time::PrimitiveDateTime;
+
+/// The most messages that can be requested at once
+pub const MAX_RECENT_MESSAGES: u64 = 5_000;
+
+#[derive(Deserialize)]
+pub struct RecentMessagesParams {
+	#[serde(default = "default_limit")]
+	limit: u64,
+	/// Leaves out the `CLEARMSG`s of deleted messages
+	#[serde(default)]
+	hide_moderation_messages: bool,
+	/// Leaves out messages that were deleted
+	#[serde(default)]
+	hide_moderated_messages: bool,
+}
+
+fn default_limit() -> u64 {
+	800
+}
+
+/// Marks a line as coming from history, like recent-messages does
+fn historical_line(
+	mut tags: Vec<(&'static str, String)>,
+	received_at: PrimitiveDateTime,
+	body: &str,
+) -> String {
+	tags.push(("historical", "1".to_string()));
+	tags.push((
+		"rm-received-ts",
+		reconstruct::timestamp_millis(received_at).to_string(),
+	));
+	tags.sort_by_key(|(key, _)| *key);
+	reconstruct::tagged_line(&tags, body)
+}
+
+/// Gets the last messages sent in a channel as raw IRC lines, along with the
+/// `CLEARMSG`s of the ones that were deleted, and any timeouts, bans and
+/// notices from the raw line archive that happened since then.
+pub async fn recent_messages(
+	State(db): State<DatabaseConnection>,
+	Path(channel): Path<String>,
+	Query(params): Query<RecentMessagesParams>,
+) -> Result<impl IntoResponse> {
+	let mut query = MessageEntity::find().filter(MessageColumn::Channel.eq(channel.to_lowercase()));
+	if params.hide_moderated_messages {
+		query = query.filter(MessageColumn::Deleted.eq(false));
+	}
+	let mut messages = query
+		.order_by_desc(MessageColumn::Timestamp)
+		.limit(params.limit.min(MAX_RECENT_MESSAGES))
+		.all(&db)
+		.await?;
+	messages.reverse();
+
+	let mut lines = Vec::with_capacity(messages.len());
+	for message in &messages {
+		lines.push((
+			message.timestamp,
+			historical_line(
+				reconstruct::message_tags(message),
+				message.timestamp,
+				&reconstruct::privmsg_body(message),
+			),
+		));
+		if params.hide_moderation_messages {
+			continue;
+		}
+		if let (Some(deleted_at), Some(tags)) =
+			(message.deleted_at, reconstruct::clearmsg_tags(message))
+		{
+			lines.push((
+				deleted_at,
+				historical_line(tags, deleted_at, &reconstruct::clearmsg_body(message)),
+			));
+		}
+	}
+	if let Some(first) = messages.first() {
+		let mut commands = vec!["USERNOTICE"];
+		if !params.hide_moderation_messages {
+			commands.push("CLEARCHAT");
+		}
+		let raw_lines = RawLineEntity::find()
+			.filter(RawLineColumn::Channel.eq(first.channel.clone()))
+			.filter(RawLineColumn::Command.is_in(commands))
+			.filter(RawLineColumn::ReceivedAt.gte(first.timestamp))
+			.order_by_asc(RawLineColumn::ReceivedAt)
+			.all(&db)
+			.await?;
+		for raw_line in raw_lines {
+			let mut message = match raw_line.line.parse::<IrcMessage>() {
+				Ok(message) => message,
+				Err(err) => {
+					warn!("failed to parse archived line {}: {}", raw_line.id, err);
+					continue;
+				}
+			};
+			let tags = message.tags.get_or_insert_with(Vec::new);
+			tags.push(Tag("historical".to_string(), Some("1".to_string())));
+			tags.push(Tag(
+				"rm-received-ts".to_string(),
+				Some(reconstruct::timestamp_millis(raw_line.received_at).to_string()),
+			));
+			lines.push((raw_line.received_at, process::irc_line(&message)));
+		}
+	}
+	lines.sort_by_key(|(timestamp, _)| *timestamp);
+
+	Ok(Json(json!({
+		"messages": lines.into_iter().map(|(_, line)| line).collect::<Vec<_>>(),
+		"error": null,
+		"error_code": null,
+	})))
+}
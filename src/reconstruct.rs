@@ -0,0 +1,108 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use entity::messages::Model as Message;
+use time::PrimitiveDateTime;
+
+/// Escapes a tag value as described in the IRCv3 message tags spec
+fn escape_tag_value(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			';' => escaped.push_str("\\:"),
+			' ' => escaped.push_str("\\s"),
+			'\\' => escaped.push_str("\\\\"),
+			'\r' => escaped.push_str("\\r"),
+			'\n' => escaped.push_str("\\n"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Rebuilds the Twitch IRC tags of a stored message, sorted by name like Twitch
+/// sends them.
+pub fn message_tags(message: &Message) -> Vec<(&'static str, String)> {
+	let mut tags = vec![
+		("badge-info", message.badge_info.clone().unwrap_or_default()),
+		("badges", message.badges.clone().unwrap_or_default()),
+		("emotes", message.emotes.clone().unwrap_or_default()),
+		("id", message.id.to_string()),
+		("mod", (message.moderator as u8).to_string()),
+	];
+	if let Some(replying_to) = message.replying_to {
+		tags.push(("reply-parent-msg-id", replying_to.to_string()));
+	}
+	tags.extend([
+		("room-id", message.room_id.to_string()),
+		("subscriber", (message.subscriber as u8).to_string()),
+		(
+			"tmi-sent-ts",
+			timestamp_millis(message.timestamp).to_string(),
+		),
+		("user-id", message.user_id.to_string()),
+		("user-type", message.user_type.clone().unwrap_or_default()),
+	]);
+	if message.vip {
+		tags.push(("vip", "1".to_string()));
+	}
+	tags
+}
+
+/// Gets the milliseconds since the Unix epoch of a timestamp, as in the
+/// `tmi-sent-ts` tag
+pub fn timestamp_millis(timestamp: PrimitiveDateTime) -> i64 {
+	(timestamp.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// Builds a raw IRC line out of its tags and the rest of the line, without a
+/// trailing newline.
+pub fn tagged_line(tags: &[(&str, String)], rest: &str) -> String {
+	let tags = tags
+		.iter()
+		.map(|(key, value)| format!("{}={}", key, escape_tag_value(value)))
+		.collect::<Vec<_>>()
+		.join(";");
+	format!("@{} {}", tags, rest)
+}
+
+/// Rebuilds everything after the tags of the raw IRC `PRIVMSG` line of a
+/// stored message.
+pub fn privmsg_body(message: &Message) -> String {
+	format!(
+		":{user}!{user}@{user}.tmi.twitch.tv PRIVMSG #{channel} :{message}",
+		user = message.username,
+		channel = message.channel,
+		message = message.message
+	)
+}
+
+/// Rebuilds the raw IRC `PRIVMSG` line of a stored message, without a
+/// trailing newline.
+pub fn privmsg_line(message: &Message) -> String {
+	tagged_line(&message_tags(message), &privmsg_body(message))
+}
+
+/// Rebuilds the tags of the `CLEARMSG` that deleted a stored message, if it
+/// was deleted.
+pub fn clearmsg_tags(message: &Message) -> Option<Vec<(&'static str, String)>> {
+	let deleted_at = message.deleted_at?;
+	Some(vec![
+		("login", message.username.clone()),
+		("room-id", message.room_id.to_string()),
+		("target-msg-id", message.id.to_string()),
+		("tmi-sent-ts", timestamp_millis(deleted_at).to_string()),
+	])
+}
+
+/// Rebuilds everything after the tags of the raw IRC `CLEARMSG` line that
+/// deleted a stored message.
+pub fn clearmsg_body(message: &Message) -> String {
+	format!(
+		":tmi.twitch.tv CLEARMSG #{} :{}",
+		message.channel, message.message
+	)
+}
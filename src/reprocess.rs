@@ -0,0 +1,164 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+	config::Config, connect_database, emotes, import, live::LiveEvent, partitions, process,
+};
+use color_eyre::eyre::{Result, WrapErr};
+use entity::raw_lines::{Column as RawLineColumn, Entity as RawLineEntity};
+use irc::proto::Message;
+use sea_orm::{
+	prelude::*, sea_query::OnConflict, ConnectionTrait, DatabaseConnection, DbBackend, EntityTrait,
+	IntoActiveModel, QueryOrder, QueryTrait, Statement,
+};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+
+/// How many archived lines to read at one time
+const RAW_LINES_PER_PAGE: usize = 10_000;
+/// How often to log progress, in lines
+const PROGRESS_INTERVAL: usize = 100_000;
+
+pub async fn reprocess(
+	config: &Config,
+	files: Vec<PathBuf>,
+	database: Option<String>,
+) -> Result<()> {
+	let target = connect_database(database.as_deref().unwrap_or(&config.database))
+		.await
+		.wrap_err("failed to connect to database")?;
+	let partitioned = partitions::is_partitioned(&target).await?;
+	// Nobody is watching replayed messages live
+	let (live_tx, _) = broadcast::channel(1);
+
+	if files.is_empty() {
+		let source = match database {
+			Some(_) => connect_database(&config.database)
+				.await
+				.wrap_err("failed to connect to source database")?,
+			None => target.clone(),
+		};
+		reprocess_archive(&source, &target, &live_tx, database.is_some(), partitioned).await?;
+		// Messages without raw lines, like imported ones, still have their
+		// emotes tag
+		let emotes = emotes::backfill_emotes(&target)
+			.await
+			.wrap_err("failed to backfill emotes")?;
+		info!("Saved {} emotes of stored messages", emotes);
+		Ok(())
+	} else {
+		for file in files {
+			reprocess_file(&target, &live_tx, &file, partitioned)
+				.await
+				.wrap_err_with(|| format!("failed to reprocess {}", file.display()))?;
+		}
+		Ok(())
+	}
+}
+
+async fn reprocess_archive(
+	source: &DatabaseConnection,
+	target: &DatabaseConnection,
+	live_tx: &broadcast::Sender<LiveEvent>,
+	copy_archive: bool,
+	partitioned: bool,
+) -> Result<()> {
+	let mut processed = 0_usize;
+	let mut skipped = 0_usize;
+	let mut raw_line_pages = RawLineEntity::find()
+		.order_by_asc(RawLineColumn::Id)
+		.paginate(source, RAW_LINES_PER_PAGE);
+	while let Some(raw_lines) = raw_line_pages
+		.fetch_and_next()
+		.await
+		.wrap_err("failed to get raw lines")?
+	{
+		for raw_line in raw_lines {
+			if copy_archive {
+				let query = RawLineEntity::insert(raw_line.clone().into_active_model())
+					.on_conflict(
+						OnConflict::column(RawLineColumn::Id)
+							.do_nothing()
+							.to_owned(),
+					)
+					.build(target.get_database_backend());
+				target
+					.execute(query)
+					.await
+					.wrap_err("failed to copy raw line")?;
+			}
+			match raw_line.line.parse::<Message>() {
+				Ok(message) => {
+					process::process_message(target, live_tx, &message, partitioned).await;
+					processed += 1;
+				}
+				Err(err) => {
+					warn!("failed to parse archived line {}: {}", raw_line.id, err);
+					skipped += 1;
+				}
+			}
+			if (processed + skipped).is_multiple_of(PROGRESS_INTERVAL) {
+				info!("Reprocessed {} archived lines so far", processed + skipped);
+			}
+		}
+	}
+	if copy_archive && target.get_database_backend() == DbBackend::Postgres {
+		// Postgres doesn't move the ID sequence along when given explicit IDs
+		target
+			.execute(Statement::from_string(
+				DbBackend::Postgres,
+				"SELECT setval(pg_get_serial_sequence('raw_lines', 'id'), COALESCE(MAX(id), 1)) \
+				 FROM raw_lines"
+					.to_string(),
+			))
+			.await
+			.wrap_err("failed to update raw line ID sequence")?;
+	}
+	info!(
+		"Reprocessed {} archived lines, skipped {} unparseable lines",
+		processed, skipped
+	);
+	Ok(())
+}
+
+/// Feeds a file of raw IRC lines through message processing, which may be
+/// compressed.
+pub async fn reprocess_file(
+	db: &DatabaseConnection,
+	live_tx: &broadcast::Sender<LiveEvent>,
+	path: &Path,
+	partitioned: bool,
+) -> Result<()> {
+	let mut lines = import::open_lines(path)
+		.await
+		.wrap_err("failed to open file")?;
+	let mut processed = 0_usize;
+	let mut skipped = 0_usize;
+	while let Some(line) = lines.next_line().await.wrap_err("failed to read line")? {
+		// Lines come without their line endings, and trailing whitespace can be
+		// part of the message
+		if line.trim().is_empty() {
+			continue;
+		}
+		match line.parse::<Message>() {
+			Ok(message) => {
+				process::process_message(db, live_tx, &message, partitioned).await;
+				processed += 1;
+			}
+			Err(err) => {
+				warn!("failed to parse line in {}: {}", path.display(), err);
+				skipped += 1;
+			}
+		}
+	}
+	info!(
+		"Reprocessed {} lines from {}, skipped {} unparseable lines",
+		processed,
+		path.display(),
+		skipped
+	);
+	Ok(())
+}
@@ -0,0 +1,187 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Deletes old messages from the database, once they're safely in rollups.
+
+use crate::{
+	config::Config,
+	manifest::{is_intact, Manifest, ManifestEntry},
+	rollup::{self, RollupPeriod},
+	timezone,
+};
+use color_eyre::eyre::{Result, WrapErr};
+use entity::messages::{Column as MessageColumn, Entity as MessageEntity};
+use sea_orm::{prelude::*, Condition, DatabaseConnection, QueryOrder};
+use std::{sync::Arc, time::Duration};
+use time::Date;
+
+/// How often to prune old messages
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Checks that every configured format of some rollups is in the manifest,
+/// has at least as many messages as the database, and is intact. Messages
+/// added after rolling up, like from imports, leave them incomplete, while
+/// messages that were already pruned, like from a stream that started the day
+/// before, don't.
+async fn is_archived(
+	config: &Config,
+	manifest: &Manifest,
+	messages: u64,
+	matches: impl Fn(&ManifestEntry) -> bool,
+) -> Result<bool> {
+	for format in &config.rollup_formats {
+		let mut archived = 0_u64;
+		for (path, entry) in manifest
+			.rollups
+			.iter()
+			.filter(|(_, entry)| entry.format == *format && matches(entry))
+		{
+			if !is_intact(config, path, entry).await? {
+				return Ok(false);
+			}
+			archived += entry.messages as u64;
+		}
+		if archived < messages {
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// Checks that every message from a channel's local day is in rollups that
+/// are intact, including the rollups of streams that overlap with it.
+pub async fn is_day_archived(
+	db: &DatabaseConnection,
+	config: &Config,
+	manifest: &Manifest,
+	channel: &str,
+	date: Date,
+) -> Result<bool> {
+	let zone = config.channel_timezone(channel);
+	let (start, end) = (
+		timezone::start_of_day(date, zone),
+		rollup::end_of_day(date, zone),
+	);
+	let streams = rollup::streams_between(db, config, start, end)
+		.await?
+		.into_iter()
+		.filter(|stream| stream.channel == channel)
+		.collect::<Vec<_>>();
+	let day_messages = MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(channel))
+		.filter(MessageColumn::Timestamp.between(start, end))
+		.filter(rollup::outside_streams(&streams))
+		.count(db)
+		.await
+		.wrap_err("failed to count messages")?;
+	if day_messages > 0
+		&& !is_archived(config, manifest, day_messages as u64, |entry| {
+			entry.channel == channel && entry.covers(date)
+		})
+		.await?
+	{
+		return Ok(false);
+	}
+	for stream in &streams {
+		if stream.ended_at.is_none() {
+			return Ok(false);
+		}
+		let stream_messages = rollup::stream_messages(stream)
+			.count(db)
+			.await
+			.wrap_err("failed to count messages")?;
+		let period = RollupPeriod::Stream(stream.id.clone());
+		// Parquet rolled up by month has streams in the months they ran in
+		let months = [
+			stream.started_at,
+			stream.ended_at.unwrap_or(stream.started_at),
+		]
+		.map(|timestamp| rollup::month_start(timezone::to_local(timestamp, zone).date()));
+		if stream_messages > 0
+			&& !is_archived(config, manifest, stream_messages as u64, |entry| {
+				entry.period == period
+					|| (entry.period == RollupPeriod::Month
+						&& entry.channel == channel
+						&& months.contains(&entry.date))
+			})
+			.await?
+		{
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// Deletes each channel's messages that are older than its retention, from
+/// days that are rolled up and intact. With `dry_run`, only logs how many
+/// messages would be deleted.
+pub async fn prune(db: &DatabaseConnection, config: &Config, dry_run: bool) -> Result<()> {
+	let manifest = Manifest::read(config).await?;
+	for (channel, days) in &config.retention {
+		let channel = channel.to_lowercase();
+		let zone = config.channel_timezone(&channel);
+		let cutoff = timezone::today(zone) - time::Duration::days(i64::from(*days));
+		let oldest = MessageEntity::find()
+			.filter(MessageColumn::Channel.eq(channel.clone()))
+			.order_by_asc(MessageColumn::Timestamp)
+			.one(db)
+			.await
+			.wrap_err("failed to get oldest message")?;
+		let mut date = match oldest {
+			Some(oldest) => timezone::to_local(oldest.timestamp, zone).date(),
+			None => continue,
+		};
+		let (mut pruned_days, mut kept_days, mut pruned) = (0_usize, 0_usize, 0_u64);
+		while date < cutoff {
+			let in_day = Condition::all()
+				.add(MessageColumn::Channel.eq(channel.clone()))
+				.add(MessageColumn::Timestamp.between(
+					timezone::start_of_day(date, zone),
+					rollup::end_of_day(date, zone),
+				));
+			let messages = MessageEntity::find()
+				.filter(in_day.clone())
+				.count(db)
+				.await
+				.wrap_err("failed to count messages")?;
+			if messages > 0 {
+				if !is_day_archived(db, config, &manifest, &channel, date).await? {
+					kept_days += 1;
+				} else if dry_run {
+					pruned += messages as u64;
+					pruned_days += 1;
+				} else {
+					pruned += MessageEntity::delete_many()
+						.filter(in_day)
+						.exec(db)
+						.await
+						.wrap_err("failed to delete messages")?
+						.rows_affected;
+					pruned_days += 1;
+				}
+			}
+			date = date.next_day().expect("ran out of days");
+		}
+		info!(
+			"{} {} messages from {} days of {}, keeping {} days that aren't rolled up and intact",
+			if dry_run { "Would prune" } else { "Pruned" },
+			pruned,
+			pruned_days,
+			channel,
+			kept_days
+		);
+	}
+	Ok(())
+}
+
+pub async fn retention_task(db: DatabaseConnection, config: Arc<Config>) {
+	loop {
+		if let Err(error) = prune(&db, &config, config.retention_dry_run).await {
+			error!("Pruning old messages failed: {:?}", error);
+		}
+		tokio::time::sleep(PRUNE_INTERVAL).await;
+	}
+}
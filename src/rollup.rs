@@ -4,13 +4,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::config::Config;
+use crate::{
+	config::{CompressionAlgorithm, Config, RollupCompression, RollupFormat, RollupGranularity},
+	manifest::{temp_path, Manifest},
+	parquet_rollup::ParquetRollup,
+	timezone, upload,
+};
 use ahash::AHashMap;
+use async_compression::{
+	tokio::write::{GzipEncoder, ZstdEncoder},
+	Level,
+};
 use color_eyre::eyre::{Result, WrapErr};
-use entity::messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message};
-use sea_orm::{prelude::*, DatabaseConnection, EntityTrait, QueryOrder};
-use std::sync::Arc;
+use entity::{
+	messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message},
+	streams::{Column as StreamColumn, Entity as StreamEntity, Model as Stream},
+};
+use sea_orm::{
+	prelude::*,
+	sea_query::{Alias, Expr, Func},
+	Condition, DatabaseConnection, EntityTrait, QueryOrder, QuerySelect, Select,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::hash_map::Entry, sync::Arc};
 use time::{macros::format_description, Date, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+use time_tz::{TimeZone, Tz};
 use tokio::{
 	fs::File,
 	io::{AsyncWriteExt, BufWriter},
@@ -20,13 +38,211 @@ use tokio::{
 /// Only read 128MB worth of messages at one time
 pub const MAX_MESSAGES_PER_PAGE: usize = (128 * 1024 * 1024) / std::mem::size_of::<Message>();
 
-fn get_text_log_file_name(channel: &str, dt: PrimitiveDateTime) -> Result<String> {
-	Ok(format!(
-		"{}_{}.txt",
-		channel,
-		dt.format(format_description!("[year]-[month]-[day]"))
-			.wrap_err("failed to format time")?
-	))
+/// The columns of CSV rollups, in the order they're written
+const CSV_HEADER: &str = concat!(
+	"id,channel,room_id,user_id,username,message,timestamp,deleted,deleted_at,",
+	"replying_to,subscriber,moderator,vip,emotes,badges,user_type,badge_info,sub_months,",
+	"bits_tier\n"
+);
+
+impl RollupFormat {
+	fn extension(self) -> &'static str {
+		match self {
+			Self::Text => "log",
+			Self::Jsonl => "jsonl",
+			Self::Csv => "csv",
+			Self::Parquet => "parquet",
+		}
+	}
+
+	fn from_extension(extension: &str) -> Option<Self> {
+		match extension {
+			"log" | "txt" => Some(Self::Text),
+			"jsonl" => Some(Self::Jsonl),
+			"csv" => Some(Self::Csv),
+			"parquet" => Some(Self::Parquet),
+			_ => None,
+		}
+	}
+}
+
+impl CompressionAlgorithm {
+	fn extension(self) -> &'static str {
+		match self {
+			Self::Gzip => "gz",
+			Self::Zstd => "zst",
+		}
+	}
+}
+
+/// A rollup file being written, compressed or not
+enum RollupWriter {
+	Plain(BufWriter<File>),
+	Gzip(GzipEncoder<BufWriter<File>>),
+	Zstd(ZstdEncoder<BufWriter<File>>),
+}
+
+impl RollupWriter {
+	fn new(file: File, compression: Option<RollupCompression>) -> Self {
+		let file = BufWriter::new(file);
+		let compression = match compression {
+			Some(compression) => compression,
+			None => return Self::Plain(file),
+		};
+		let level = compression
+			.level
+			.map(Level::Precise)
+			.unwrap_or(Level::Default);
+		match compression.algorithm {
+			CompressionAlgorithm::Gzip => Self::Gzip(GzipEncoder::with_quality(file, level)),
+			CompressionAlgorithm::Zstd => Self::Zstd(ZstdEncoder::with_quality(file, level)),
+		}
+	}
+
+	async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+		match self {
+			Self::Plain(writer) => writer.write_all(buf).await,
+			Self::Gzip(writer) => writer.write_all(buf).await,
+			Self::Zstd(writer) => writer.write_all(buf).await,
+		}
+	}
+
+	/// Finishes compressing, and makes sure everything is on disk
+	async fn finish(self) -> std::io::Result<()> {
+		let mut file = match self {
+			Self::Plain(writer) => writer,
+			Self::Gzip(mut writer) => {
+				writer.shutdown().await?;
+				writer.into_inner()
+			}
+			Self::Zstd(mut writer) => {
+				writer.shutdown().await?;
+				writer.into_inner()
+			}
+		};
+		file.flush().await?;
+		file.into_inner().sync_all().await
+	}
+}
+
+/// The part of a channel's chat a rollup file covers
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum RollupPeriod {
+	/// A whole day
+	#[default]
+	Day,
+	/// An hour of a day, from 0 to 23
+	Hour(u8),
+	/// A stream, by its ID
+	Stream(String),
+	/// A whole month, dated by its first day. Only Parquet rollups that are
+	/// partitioned by month cover one.
+	Month,
+}
+
+/// Gets the first day of a date's month
+pub fn month_start(date: Date) -> Date {
+	date.replace_day(1).expect("every month has a first day")
+}
+
+/// Gets the last day of a date's month
+pub fn month_end(date: Date) -> Date {
+	date.replace_day(time::util::days_in_year_month(date.year(), date.month()))
+		.expect("every month has a last day")
+}
+
+pub fn get_log_file_name(
+	channel: &str,
+	date: Date,
+	period: &RollupPeriod,
+	format: RollupFormat,
+	compression: Option<RollupCompression>,
+) -> Result<String> {
+	let date = match period {
+		RollupPeriod::Month => date.format(format_description!("[year]-[month]")),
+		_ => date.format(format_description!("[year]-[month]-[day]")),
+	}
+	.wrap_err("failed to format date")?;
+	let period = match period {
+		RollupPeriod::Day | RollupPeriod::Month => String::new(),
+		RollupPeriod::Hour(hour) => format!("_{:02}", hour),
+		RollupPeriod::Stream(id) => format!("_stream-{}", id),
+	};
+	let mut file_name = format!("{}_{}{}.{}", channel, date, period, format.extension());
+	if let Some(compression) = compression {
+		file_name.push('.');
+		file_name.push_str(compression.algorithm.extension());
+	}
+	Ok(file_name)
+}
+
+/// What a rollup file holds, going by its name
+pub struct LogFileName {
+	pub channel: String,
+	pub date: Date,
+	pub period: RollupPeriod,
+	pub format: RollupFormat,
+}
+
+/// Gets what a rollup file holds from its name, which is in the format made by
+/// [`get_log_file_name`].
+pub fn parse_log_file_name(file_name: &str) -> Option<LogFileName> {
+	// Unfinished rollups
+	if file_name.ends_with(".tmp") {
+		return None;
+	}
+	let date_format = format_description!("[year]-[month]-[day]");
+	let mut parts = file_name.split('.');
+	let (rest, last) = parts.next()?.rsplit_once('_')?;
+	let format = RollupFormat::from_extension(parts.next()?)?;
+	if let Ok(date) = Date::parse(last, date_format) {
+		return Some(LogFileName {
+			channel: rest.to_string(),
+			date,
+			period: RollupPeriod::Day,
+			format,
+		});
+	}
+	if let Some(date) = parse_month(last) {
+		return Some(LogFileName {
+			channel: rest.to_string(),
+			date,
+			period: RollupPeriod::Month,
+			format,
+		});
+	}
+	let period = match last.strip_prefix("stream-") {
+		Some(id) => RollupPeriod::Stream(id.to_string()),
+		None if last.len() == 2 => RollupPeriod::Hour(last.parse().ok().filter(|hour| *hour < 24)?),
+		None => return None,
+	};
+	let (channel, date) = rest.rsplit_once('_')?;
+	Some(LogFileName {
+		channel: channel.to_string(),
+		date: Date::parse(date, date_format).ok()?,
+		period,
+		format,
+	})
+}
+
+/// Parses a month like `2022-09` into its first day
+fn parse_month(month: &str) -> Option<Date> {
+	let (year, month) = month.split_once('-')?;
+	if year.len() != 4 || month.len() != 2 {
+		return None;
+	}
+	Date::from_calendar_date(
+		year.parse().ok()?,
+		time::Month::try_from(month.parse::<u8>().ok()?).ok()?,
+		1,
+	)
+	.ok()
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+enum ChannelDayQueryAs {
+	Channel,
+	Day,
 }
 
 pub async fn rollup_task(
@@ -34,50 +250,64 @@ pub async fn rollup_task(
 	config: Arc<Config>,
 	mut rx: mpsc::UnboundedReceiver<()>,
 ) {
+	if let Err(error) = backfill_rollups(&db, &config).await {
+		error!("Backfilling rollups failed: {:?}", error);
+	}
 	loop {
-		let todays_date = OffsetDateTime::now_utc();
-		let next_midnight = todays_date.replace_time(Time::MIDNIGHT) + Duration::days(1);
-		let time_til_next_midnight = next_midnight - todays_date;
+		let now = OffsetDateTime::now_utc();
+		let now = PrimitiveDateTime::new(now.date(), now.time());
+		// Each timezone's channels are rolled up when its day ends
+		let zones = config
+			.rollup_timezones()
+			.into_iter()
+			.map(|zone| {
+				let today = timezone::to_local(now, zone).date();
+				let tomorrow = today.next_day().expect("ran out of days");
+				(zone, today, timezone::start_of_day(tomorrow, zone))
+			})
+			.collect::<Vec<_>>();
+		let next_midnight = zones
+			.iter()
+			.map(|(_, _, midnight)| *midnight)
+			.min()
+			.expect("there's always UTC");
+		let time_til_next_midnight = next_midnight - now;
 
 		info!(
 			"Waiting {} seconds until next rollup",
 			time_til_next_midnight.whole_seconds()
 		);
-		tokio::select! {
-			_ = tokio::time::sleep(time_til_next_midnight.unsigned_abs()) => {},
-			_ = rx.recv() => {},
-		}
-		info!(
-			"Starting rollup for {}",
-			todays_date
-				.format(format_description!("[year]-[month]-[day]"))
-				.unwrap()
-		);
-		if let Err(error) = rollup_everything(&db, &config, todays_date.date())
-			.await
-			.wrap_err_with(|| {
-				format!(
-					"rollup for {} failed",
-					todays_date
-						.format(format_description!("[year]-[month]-[day]"))
-						.unwrap()
-				)
-			}) {
-			error!("Rollup failed: {:?}", error);
+		let signalled = tokio::select! {
+			_ = tokio::time::sleep(time_til_next_midnight.unsigned_abs()) => false,
+			_ = rx.recv() => true,
+		};
+		for (zone, date, midnight) in zones {
+			// A signal rolls up every timezone's day so far
+			if !signalled && midnight != next_midnight {
+				continue;
+			}
+			info!("Starting rollup for {} in {}", date, zone.name());
+			if let Err(error) = rollup_day(&db, &config, zone, date, &zone_channels(&config, zone))
+				.await
+				.wrap_err_with(|| format!("rollup for {} in {} failed", date, zone.name()))
+			{
+				error!("Rollup failed: {:?}", error);
+			}
 		}
 	}
 }
 
-fn format_message(message: &Message) -> String {
+/// Formats a message for text rollups, with its times in the channel's
+/// timezone.
+fn format_message(message: &Message, zone: &Tz) -> String {
 	if let Some(deleted_at) = message.deleted_at {
 		format!(
 			"[{}] <{}; deleted at {}> {}\n",
-			message
-				.timestamp
+			timezone::to_local(message.timestamp, zone)
 				.format(format_description!("[hour]:[minute]:[second]"))
 				.expect("failed to format time"),
 			message.username,
-			deleted_at
+			timezone::to_local(deleted_at, zone)
 				.format(format_description!("[hour]:[minute]:[second]"))
 				.expect("failed to format time"),
 			message.message
@@ -85,8 +315,7 @@ fn format_message(message: &Message) -> String {
 	} else {
 		format!(
 			"[{}] <{}> {}\n",
-			message
-				.timestamp
+			timezone::to_local(message.timestamp, zone)
 				.format(format_description!("[hour]:[minute]:[second]"))
 				.expect("failed to format time"),
 			message.username,
@@ -95,14 +324,480 @@ fn format_message(message: &Message) -> String {
 	}
 }
 
-async fn rollup_everything(db: &DatabaseConnection, config: &Config, date: Date) -> Result<()> {
-	let mut files = AHashMap::<String, BufWriter<File>>::new();
+fn format_json_message(message: &Message) -> String {
+	let mut json = serde_json::to_string(message).expect("failed to serialize message");
+	json.push('\n');
+	json
+}
+
+/// Quotes a CSV field if it needs to be
+fn escape_csv_field(field: &str) -> String {
+	if field.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_string()
+	}
+}
+
+fn format_csv_message(message: &Message) -> String {
+	let optional = |value: Option<String>| value.unwrap_or_default();
+	let fields = [
+		message.id.to_string(),
+		message.channel.clone(),
+		message.room_id.to_string(),
+		message.user_id.to_string(),
+		message.username.clone(),
+		message.message.clone(),
+		message.timestamp.to_string(),
+		message.deleted.to_string(),
+		optional(message.deleted_at.map(|deleted_at| deleted_at.to_string())),
+		optional(
+			message
+				.replying_to
+				.map(|replying_to| replying_to.to_string()),
+		),
+		message.subscriber.to_string(),
+		message.moderator.to_string(),
+		message.vip.to_string(),
+		optional(message.emotes.clone()),
+		optional(message.badges.clone()),
+		optional(message.user_type.clone()),
+		optional(message.badge_info.clone()),
+		optional(message.sub_months.map(|sub_months| sub_months.to_string())),
+		optional(message.bits_tier.map(|bits_tier| bits_tier.to_string())),
+	];
+	let mut line = fields
+		.iter()
+		.map(|field| escape_csv_field(field))
+		.collect::<Vec<_>>()
+		.join(",");
+	line.push('\n');
+	line
+}
+
+/// A message read back from a text log, which only has what
+/// [`format_message`] wrote out.
+pub struct TextLogMessage {
+	pub username: String,
+	pub message: String,
+	pub timestamp: PrimitiveDateTime,
+	pub deleted_at: Option<PrimitiveDateTime>,
+}
+
+/// Parses a line written by [`format_message`], given the date of the log
+/// file it's from and the timezone its times are in. Times are returned in UTC.
+pub fn parse_text_log_line(line: &str, date: Date, zone: &Tz) -> Option<TextLogMessage> {
+	let time_format = format_description!("[hour]:[minute]:[second]");
+	let (time, rest) = line.strip_prefix('[')?.split_once("] <")?;
+	let (user, message) = rest.split_once("> ")?;
+	let timestamp = date.with_time(Time::parse(time, time_format).ok()?);
+	let (username, deleted_at) = match user.split_once("; deleted at ") {
+		Some((username, deleted_at)) => {
+			let mut deleted_at = date.with_time(Time::parse(deleted_at, time_format).ok()?);
+			// Deleted after midnight
+			if deleted_at < timestamp {
+				deleted_at += Duration::days(1);
+			}
+			(username, Some(deleted_at))
+		}
+		None => (user, None),
+	};
+	Some(TextLogMessage {
+		username: username.to_string(),
+		message: message.to_string(),
+		timestamp: timezone::from_local(timestamp, zone),
+		deleted_at: deleted_at.map(|deleted_at| timezone::from_local(deleted_at, zone)),
+	})
+}
+
+/// A local day or month of a timezone, and the channels with messages in it
+/// that are missing rollups
+type IncompleteRollup = (&'static Tz, Date, Vec<String>);
+
+fn has_incomplete(incomplete: &[IncompleteRollup], zone: &Tz, date: Date, channel: &str) -> bool {
+	incomplete.iter().any(|(other, other_date, channels)| {
+		timezone::same_zone(zone, other)
+			&& *other_date == date
+			&& channels.iter().any(|other| other == channel)
+	})
+}
+
+fn add_incomplete(
+	incomplete: &mut Vec<IncompleteRollup>,
+	zone: &'static Tz,
+	date: Date,
+	channel: &str,
+) {
+	match incomplete
+		.iter_mut()
+		.find(|(other, other_date, _)| timezone::same_zone(zone, other) && *other_date == date)
+	{
+		Some((_, _, channels)) => channels.push(channel.to_string()),
+		None => incomplete.push((zone, date, vec![channel.to_string()])),
+	}
+}
+
+/// Gets every UTC day that each channel has messages on
+async fn channel_days(db: &DatabaseConnection) -> Result<Vec<(String, Date)>> {
+	let day = Func::cust(Alias::new("DATE")).arg(Expr::col(MessageColumn::Timestamp));
+	MessageEntity::find()
+		.select_only()
+		.column_as(MessageColumn::Channel, ChannelDayQueryAs::Channel)
+		.column_as(day.clone(), ChannelDayQueryAs::Day)
+		.group_by(MessageColumn::Channel)
+		.group_by(day)
+		.into_values::<(String, Date), ChannelDayQueryAs>()
+		.all(db)
+		.await
+		.wrap_err("failed to get days with messages")
+}
+
+/// Gets the local days that a UTC day overlaps with, which might not both have
+/// messages
+fn local_dates(utc_date: Date, zone: &Tz) -> Vec<Date> {
+	let start = utc_date.with_time(Time::MIDNIGHT);
+	let mut local_dates = vec![timezone::to_local(start, zone).date()];
+	let last_local_date =
+		timezone::to_local(start + Duration::days(1) - Duration::nanoseconds(1), zone).date();
+	if last_local_date != local_dates[0] {
+		local_dates.push(last_local_date);
+	}
+	local_dates
+}
+
+/// Finds the local days of each timezone that have messages in a channel, but
+/// not every configured rollup for that channel in the manifest. Parquet
+/// rollups that are written a month at a time are left to [`missing_months`].
+async fn incomplete_days(
+	db: &DatabaseConnection,
+	config: &Config,
+) -> Result<Vec<IncompleteRollup>> {
+	let manifest = Manifest::read(config).await?;
+	let formats = config.period_formats();
+	let is_complete = |channel: &str, date: Date| {
+		formats
+			.iter()
+			.all(|format| manifest.is_rolled_up(config, channel, date, *format))
+	};
+	let mut incomplete = Vec::<IncompleteRollup>::new();
+	for (channel, utc_date) in channel_days(db).await? {
+		let zone = config.channel_timezone(&channel);
+		for date in local_dates(utc_date, zone) {
+			if is_complete(&channel, date) || has_incomplete(&incomplete, zone, date, &channel) {
+				continue;
+			}
+			let is_stream_channel =
+				config.channel_granularity(&channel) == RollupGranularity::Stream;
+			if !timezone::same_zone(zone, timezone::utc()) || is_stream_channel {
+				let (start, end) = (timezone::start_of_day(date, zone), end_of_day(date, zone));
+				let has_messages = MessageEntity::find()
+					.filter(MessageColumn::Channel.eq(channel.clone()))
+					.filter(MessageColumn::Timestamp.between(start, end))
+					.filter(outside_streams(
+						&streams_between(db, config, start, end).await?,
+					))
+					.one(db)
+					.await
+					.wrap_err("failed to check for messages")?
+					.is_some();
+				if !has_messages {
+					continue;
+				}
+			}
+			add_incomplete(&mut incomplete, zone, date, &channel);
+		}
+	}
+	incomplete.sort_by_key(|(_, date, _)| *date);
+	Ok(incomplete)
+}
+
+/// Finds the local months before this one that have messages in a channel,
+/// but no Parquet rollup, when Parquet rollups are written a month at a time.
+/// Each month is dated by its first day.
+async fn missing_months(db: &DatabaseConnection, config: &Config) -> Result<Vec<IncompleteRollup>> {
+	if !config.parquet_by_month() {
+		return Ok(Vec::new());
+	}
+	let manifest = Manifest::read(config).await?;
+	let mut missing = Vec::<IncompleteRollup>::new();
+	for (channel, utc_date) in channel_days(db).await? {
+		let zone = config.channel_timezone(&channel);
+		for date in local_dates(utc_date, zone) {
+			let month = month_start(date);
+			if month_end(month) >= timezone::today(zone)
+				|| manifest.is_rolled_up(config, &channel, month, RollupFormat::Parquet)
+				|| has_incomplete(&missing, zone, month, &channel)
+			{
+				continue;
+			}
+			if !timezone::same_zone(zone, timezone::utc()) {
+				let has_messages = MessageEntity::find()
+					.filter(MessageColumn::Channel.eq(channel.clone()))
+					.filter(MessageColumn::Timestamp.between(
+						timezone::start_of_day(month, zone),
+						end_of_day(month_end(month), zone),
+					))
+					.one(db)
+					.await
+					.wrap_err("failed to check for messages")?
+					.is_some();
+				if !has_messages {
+					continue;
+				}
+			}
+			add_incomplete(&mut missing, zone, month, &channel);
+		}
+	}
+	missing.sort_by_key(|(_, month, _)| *month);
+	Ok(missing)
+}
+
+/// Finds the days before today that have messages in a channel, but not every
+/// configured rollup for that channel.
+pub async fn missing_rollups(
+	db: &DatabaseConnection,
+	config: &Config,
+) -> Result<Vec<IncompleteRollup>> {
+	let mut missing = incomplete_days(db, config).await?;
+	missing.retain(|(zone, date, _)| *date < timezone::today(zone));
+	Ok(missing)
+}
+
+/// Finds the streams that have ended, but don't have every configured rollup
+async fn missing_streams(db: &DatabaseConnection, config: &Config) -> Result<Vec<Stream>> {
+	let manifest = Manifest::read(config).await?;
+	let streams = StreamEntity::find()
+		.filter(StreamColumn::Channel.is_in(config.stream_channels()))
+		.filter(StreamColumn::EndedAt.is_not_null())
+		.order_by_asc(StreamColumn::StartedAt)
+		.all(db)
+		.await
+		.wrap_err("failed to get streams")?;
+	let mut missing = Vec::new();
+	for stream in streams {
+		if config
+			.period_formats()
+			.iter()
+			.all(|format| manifest.is_stream_rolled_up(config, &stream.id, *format))
+		{
+			continue;
+		}
+		// Streams without any chat never get a rollup
+		let has_messages = stream_messages(&stream)
+			.one(db)
+			.await
+			.wrap_err("failed to check for messages")?
+			.is_some();
+		if has_messages {
+			missing.push(stream);
+		}
+	}
+	Ok(missing)
+}
+
+/// Rolls up every day and stream that's missing a rollup, such as from the
+/// logger being down at midnight.
+pub async fn backfill_rollups(db: &DatabaseConnection, config: &Config) -> Result<()> {
+	let missing = missing_rollups(db, config).await?;
+	if !missing.is_empty() {
+		info!("Backfilling rollups for {} days", missing.len());
+	}
+	for (zone, date, channels) in missing {
+		rollup_day(db, config, zone, date, &channels)
+			.await
+			.wrap_err_with(|| format!("rollup for {} in {} failed", date, zone.name()))?;
+	}
+	let missing_streams = missing_streams(db, config).await?;
+	if !missing_streams.is_empty() {
+		info!("Backfilling rollups for {} streams", missing_streams.len());
+	}
+	for stream in missing_streams {
+		rollup_stream(db, config, &stream)
+			.await
+			.wrap_err_with(|| format!("rollup for stream {} failed", stream.id))?;
+	}
+	let missing_months = missing_months(db, config).await?;
+	if !missing_months.is_empty() {
+		info!(
+			"Backfilling Parquet rollups for {} months",
+			missing_months.len()
+		);
+	}
+	for (zone, month, channels) in missing_months {
+		rollup_month(db, config, zone, month, &channels)
+			.await
+			.wrap_err_with(|| format!("rollup for {} in {} failed", month, zone.name()))?;
+	}
+	Ok(())
+}
+
+/// Rolls up every day from `from` to `to`, inclusive, along with the streams
+/// that started on them. Days and streams that are already rolled up are
+/// skipped, unless `force` is set, which rolls up the logged channels' days
+/// as well as any others that are missing rollups.
+pub async fn rollup_range(
+	db: &DatabaseConnection,
+	config: &Config,
+	from: Date,
+	to: Date,
+	force: bool,
+) -> Result<()> {
+	let incomplete = incomplete_days(db, config).await?;
+	let mut date = from;
+	while date <= to {
+		for zone in config.rollup_timezones() {
+			let mut channels = incomplete
+				.iter()
+				.find(|(other, other_date, _)| {
+					timezone::same_zone(zone, other) && *other_date == date
+				})
+				.map(|(_, _, channels)| channels.clone())
+				.unwrap_or_default();
+			if force {
+				for channel in zone_channels(config, zone) {
+					if !channels.contains(&channel) {
+						channels.push(channel);
+					}
+				}
+			}
+			if !channels.is_empty() {
+				rollup_day(db, config, zone, date, &channels)
+					.await
+					.wrap_err_with(|| format!("rollup for {} in {} failed", date, zone.name()))?;
+			} else if !force {
+				info!(
+					"Skipping rollup for {} in {}, it's already rolled up",
+					date,
+					zone.name()
+				);
+			}
+		}
+		date = match date.next_day() {
+			Some(date) => date,
+			None => break,
+		};
+	}
+
+	let missing_streams = missing_streams(db, config).await?;
+	let streams = StreamEntity::find()
+		.filter(StreamColumn::Channel.is_in(config.stream_channels()))
+		.filter(StreamColumn::EndedAt.is_not_null())
+		.order_by_asc(StreamColumn::StartedAt)
+		.all(db)
+		.await
+		.wrap_err("failed to get streams")?;
+	for stream in streams {
+		let zone = config.channel_timezone(&stream.channel);
+		let date = timezone::to_local(stream.started_at, zone).date();
+		if date < from || date > to {
+			continue;
+		}
+		if force
+			|| missing_streams
+				.iter()
+				.any(|missing| missing.id == stream.id)
+		{
+			rollup_stream(db, config, &stream)
+				.await
+				.wrap_err_with(|| format!("rollup for stream {} failed", stream.id))?;
+		} else {
+			info!(
+				"Skipping rollup for stream {}, it's already rolled up",
+				stream.id
+			);
+		}
+	}
+
+	// Months are rolled up along with their last day, so this only catches the
+	// ones whose last day was skipped
+	for (zone, month, channels) in missing_months(db, config).await? {
+		if (from..=to).contains(&month_end(month)) {
+			rollup_month(db, config, zone, month, &channels)
+				.await
+				.wrap_err_with(|| format!("rollup for {} in {} failed", month, zone.name()))?;
+		}
+	}
+	Ok(())
+}
+
+/// Gets the last moment of a local day, in UTC
+pub fn end_of_day(date: Date, zone: &Tz) -> PrimitiveDateTime {
+	let tomorrow = date.next_day().expect("ran out of days");
+	timezone::start_of_day(tomorrow, zone) - Duration::nanoseconds(1)
+}
+
+/// Gets the streams of channels with a file for each stream that overlap with
+/// a span of time
+pub async fn streams_between(
+	db: &DatabaseConnection,
+	config: &Config,
+	start: PrimitiveDateTime,
+	end: PrimitiveDateTime,
+) -> Result<Vec<Stream>> {
+	StreamEntity::find()
+		.filter(StreamColumn::Channel.is_in(config.stream_channels()))
+		.filter(StreamColumn::StartedAt.lte(end))
+		.filter(
+			Condition::any()
+				.add(StreamColumn::EndedAt.is_null())
+				.add(StreamColumn::EndedAt.gte(start)),
+		)
+		.all(db)
+		.await
+		.wrap_err("failed to get streams")
+}
+
+/// Leaves out the messages that are rolled up with a stream instead of a day,
+/// including those of streams that haven't ended yet.
+pub fn outside_streams(streams: &[Stream]) -> Condition {
+	streams.iter().fold(Condition::all(), |condition, stream| {
+		let mut outside = Condition::any()
+			.add(MessageColumn::Channel.ne(stream.channel.clone()))
+			.add(MessageColumn::Timestamp.lt(stream.started_at));
+		if let Some(ended_at) = stream.ended_at {
+			outside = outside.add(MessageColumn::Timestamp.gt(ended_at));
+		}
+		condition.add(outside)
+	})
+}
+
+/// Finds the messages sent during a stream
+pub fn stream_messages(stream: &Stream) -> Select<MessageEntity> {
+	let mut query = MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(stream.channel.clone()))
+		.filter(MessageColumn::Timestamp.gte(stream.started_at));
+	if let Some(ended_at) = stream.ended_at {
+		query = query.filter(MessageColumn::Timestamp.lte(ended_at));
+	}
+	query
+}
+
+/// A rollup file being written
+struct OpenRollup {
+	writer: RollupWriter,
+	messages: usize,
+}
+
+/// Writes the messages a query finds into rollups of some formats, with
+/// `period` picking the date and period of the file each message goes in.
+/// Files are written to temporary paths, and only moved into place and
+/// recorded in the manifest once they're complete.
+async fn write_rollups(
+	db: &DatabaseConnection,
+	config: &Config,
+	zone: &Tz,
+	query: Select<MessageEntity>,
+	formats: &[RollupFormat],
+	period: impl Fn(&Message) -> (Date, RollupPeriod),
+) -> Result<()> {
+	if formats.is_empty() {
+		return Ok(());
+	}
+	let mut files = AHashMap::<String, OpenRollup>::new();
+	let mut parquet_files = AHashMap::<String, (ParquetRollup, usize)>::new();
 	let mut messages_saved = AHashMap::<String, usize>::new();
-	let start_of_day = date.with_time(Time::MIDNIGHT);
-	let end_of_day = start_of_day + Duration::days(1) - Duration::nanoseconds(1);
 
-	let mut message_pages = MessageEntity::find()
-		.filter(MessageColumn::Timestamp.between(start_of_day, end_of_day))
+	let mut message_pages = query
 		.order_by_asc(MessageColumn::Timestamp)
 		.paginate(db, MAX_MESSAGES_PER_PAGE);
 
@@ -111,40 +806,233 @@ async fn rollup_everything(db: &DatabaseConnection, config: &Config, date: Date)
 		.await
 		.wrap_err("failed to get messages")?
 	{
+		if formats.contains(&RollupFormat::Parquet) {
+			let mut batches = AHashMap::<String, (Date, RollupPeriod, Vec<&Message>)>::new();
+			for message in &messages {
+				let (date, period) = period(message);
+				let file_name = get_log_file_name(
+					&message.channel,
+					date,
+					&period,
+					RollupFormat::Parquet,
+					None,
+				)?;
+				batches
+					.entry(file_name)
+					.or_insert_with(|| (date, period, Vec::new()))
+					.2
+					.push(message);
+			}
+			for (file_name, (date, period, messages)) in batches {
+				let channel = &messages[0].channel;
+				let (file, saved) = match parquet_files.entry(file_name) {
+					Entry::Occupied(entry) => entry.into_mut(),
+					Entry::Vacant(entry) => {
+						entry.insert((ParquetRollup::create(config, channel, date, &period)?, 0))
+					}
+				};
+				file.write(&messages)?;
+				*saved += messages.len();
+			}
+		}
 		for message in messages {
-			let file_name = get_text_log_file_name(&message.channel, message.timestamp)
-				.wrap_err("failed to get text log file name for message")?;
-			let file = files.entry(file_name.clone()).or_insert_with(|| {
-				let file =
-					std::fs::File::create(config.rollup_dir.join(&file_name).with_extension("log"))
-						.expect("failed to create log file");
-				BufWriter::new(File::from_std(file))
-			});
+			let (date, period) = period(&message);
+			for format in formats {
+				if *format == RollupFormat::Parquet {
+					continue;
+				}
+				let file_name = get_log_file_name(
+					&message.channel,
+					date,
+					&period,
+					*format,
+					config.rollup_compression,
+				)
+				.wrap_err("failed to get log file name for message")?;
+				let file = match files.entry(file_name) {
+					Entry::Occupied(entry) => entry.into_mut(),
+					Entry::Vacant(entry) => {
+						let path = temp_path(&config.rollup_dir.join(entry.key()));
+						let file = std::fs::File::create(&path).wrap_err_with(|| {
+							format!("failed to create log file {}", path.display())
+						})?;
+						let file = entry.insert(OpenRollup {
+							writer: RollupWriter::new(
+								File::from_std(file),
+								config.rollup_compression,
+							),
+							messages: 0,
+						});
+						if *format == RollupFormat::Csv {
+							file.writer
+								.write_all(CSV_HEADER.as_bytes())
+								.await
+								.wrap_err("failed to write to log file")?;
+						}
+						file
+					}
+				};
+				let chat_message = match format {
+					RollupFormat::Text => format_message(&message, zone),
+					RollupFormat::Jsonl => format_json_message(&message),
+					RollupFormat::Csv => format_csv_message(&message),
+					RollupFormat::Parquet => unreachable!(),
+				};
+				file.writer
+					.write_all(&chat_message.into_bytes())
+					.await
+					.wrap_err("failed to write to log file")?;
+				file.messages += 1;
+			}
 			messages_saved
 				.entry(message.channel.clone())
 				.and_modify(|x| *x += 1)
 				.or_insert(1);
-			let chat_message = format_message(&message);
-			file.write_all(&chat_message.into_bytes())
-				.await
-				.wrap_err("failed to write to log file")?;
 		}
 	}
 
-	for (user, mut file) in files.drain() {
-		file.flush()
+	for (file_name, file) in files.drain() {
+		file.writer
+			.finish()
 			.await
-			.wrap_err_with(|| format!("failed to flush buffer for log file for {}", user))?;
-		file.into_inner()
-			.sync_all()
-			.await
-			.wrap_err_with(|| format!("failed to sync log file for {}", user))?;
-		info!("Rolled up user '{}' for {}", user, date);
+			.wrap_err_with(|| format!("failed to finish log file {}", file_name))?;
+		Manifest::finish_rollup(config, &config.rollup_dir.join(&file_name), file.messages).await?;
+		info!("Rolled up '{}'", file_name);
+	}
+
+	for (file_name, (file, saved)) in parquet_files.drain() {
+		let path = file
+			.finish()
+			.wrap_err_with(|| format!("failed to finish Parquet rollup {}", file_name))?;
+		Manifest::finish_rollup(config, &path, saved).await?;
+		info!("Rolled up '{}'", file_name);
 	}
 
 	for (user, messages) in messages_saved {
 		info!("Rolled up {} messages for {}", messages, user);
 	}
 
+	// Failed uploads are tried again after the next rollup
+	if let Err(error) = upload::upload_rollups(config).await {
+		error!("Uploading rollups failed: {:?}", error);
+	}
+
+	Ok(())
+}
+
+/// Gets the channels that use a timezone, out of the configured ones. Channels
+/// without a timezone of their own use UTC, so for UTC that's every logged
+/// channel that isn't configured with another timezone. Other channels, like
+/// imported ones, are rolled up by backfilling.
+fn zone_channels(config: &Config, zone: &Tz) -> Vec<String> {
+	if !timezone::same_zone(zone, timezone::utc()) {
+		return config.timezone_channels(zone);
+	}
+	config
+		.twitch
+		.channels
+		.iter()
+		.map(|channel| channel.to_lowercase())
+		.filter(|channel| timezone::same_zone(config.channel_timezone(channel), zone))
+		.collect()
+}
+
+/// Rolls up the messages from a local day of some channels that use a
+/// timezone. Messages from channels with a file for each stream are left out
+/// if they were sent during one.
+pub async fn rollup_day(
+	db: &DatabaseConnection,
+	config: &Config,
+	zone: &Tz,
+	date: Date,
+	channels: &[String],
+) -> Result<()> {
+	let (start, end) = (timezone::start_of_day(date, zone), end_of_day(date, zone));
+	// Filtering by channel first lets the channel and timestamp index be used
+	let query = MessageEntity::find()
+		.filter(MessageColumn::Channel.is_in(channels.to_vec()))
+		.filter(MessageColumn::Timestamp.between(start, end))
+		.filter(outside_streams(
+			&streams_between(db, config, start, end).await?,
+		));
+	write_rollups(
+		db,
+		config,
+		zone,
+		query,
+		&config.period_formats(),
+		|message| {
+			let period = match config.channel_granularity(&message.channel) {
+				RollupGranularity::Hour => {
+					RollupPeriod::Hour(timezone::to_local(message.timestamp, zone).hour())
+				}
+				RollupGranularity::Day | RollupGranularity::Stream => RollupPeriod::Day,
+			};
+			(date, period)
+		},
+	)
+	.await?;
+	if config.parquet_by_month() && date == month_end(date) && date < timezone::today(zone) {
+		rollup_month(db, config, zone, month_start(date), channels).await?;
+	}
+	Ok(())
+}
+
+/// Rolls up a local month of some channels that use a timezone into Parquet,
+/// for when it's written a month at a time. Each channel gets one file with
+/// every message of the month, including those sent during streams.
+pub async fn rollup_month(
+	db: &DatabaseConnection,
+	config: &Config,
+	zone: &Tz,
+	month: Date,
+	channels: &[String],
+) -> Result<()> {
+	info!("Starting Parquet rollup for {} in {}", month, zone.name());
+	let query = MessageEntity::find()
+		.filter(MessageColumn::Channel.is_in(channels.to_vec()))
+		.filter(MessageColumn::Timestamp.between(
+			timezone::start_of_day(month, zone),
+			end_of_day(month_end(month), zone),
+		));
+	write_rollups(db, config, zone, query, &[RollupFormat::Parquet], |_| {
+		(month, RollupPeriod::Month)
+	})
+	.await
+}
+
+/// Rolls up the messages sent during a stream. Its files are dated by the
+/// local day it started on.
+pub async fn rollup_stream(
+	db: &DatabaseConnection,
+	config: &Config,
+	stream: &Stream,
+) -> Result<()> {
+	info!(
+		"Starting rollup for stream {} of {}",
+		stream.id, stream.channel
+	);
+	let zone = config.channel_timezone(&stream.channel);
+	let date = timezone::to_local(stream.started_at, zone).date();
+	let period = RollupPeriod::Stream(stream.id.clone());
+	write_rollups(
+		db,
+		config,
+		zone,
+		stream_messages(stream),
+		&config.period_formats(),
+		|_| (date, period.clone()),
+	)
+	.await
+}
+
+/// Rolls up the messages from a day of every logged channel, using each
+/// channel's own timezone.
+pub async fn rollup_everything(db: &DatabaseConnection, config: &Config, date: Date) -> Result<()> {
+	for zone in config.rollup_timezones() {
+		rollup_day(db, config, zone, date, &zone_channels(config, zone))
+			.await
+			.wrap_err_with(|| format!("rollup in {} failed", zone.name()))?;
+	}
 	Ok(())
 }
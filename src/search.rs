@@ -0,0 +1,74 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+	archive,
+	cli::SearchFormat,
+	config::Config,
+	open_database,
+	server::{self, convert_query_to_datetime},
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use futures_util::{pin_mut, StreamExt};
+use std::io::Write;
+use time::PrimitiveDateTime;
+
+/// Parses a time given on the command line, if there is one
+pub fn parse_time(time: Option<&str>) -> Result<Option<PrimitiveDateTime>> {
+	match time {
+		Some(time) => convert_query_to_datetime(Some(time))
+			.map(Some)
+			.ok_or_else(|| {
+				eyre!(
+					"'{}' isn't a Unix timestamp, RFC 3339 or RFC 2822 time",
+					time
+				)
+			}),
+		None => Ok(None),
+	}
+}
+
+/// Searches a channel's messages straight from the database, and the rollups
+/// of days that have been pruned from it, printing them the same way the
+/// search API does or as NDJSON.
+pub async fn search(
+	config: &Config,
+	channel: String,
+	users: Vec<String>,
+	start: Option<String>,
+	end: Option<String>,
+	format: SearchFormat,
+) -> Result<()> {
+	let start_time = parse_time(start.as_deref())?;
+	let end_time = parse_time(end.as_deref())?;
+	let db = open_database(&config.database)
+		.await
+		.wrap_err("failed to connect to database")?;
+
+	let messages = archive::search(
+		&db,
+		config,
+		&channel,
+		&users,
+		start_time,
+		end_time,
+		u64::MAX,
+	);
+	pin_mut!(messages);
+	let stdout = std::io::stdout();
+	let mut stdout = stdout.lock();
+	while let Some(message) = messages.next().await {
+		let message = message?;
+		match format {
+			SearchFormat::Text => stdout.write_all(server::format_message(&message).as_bytes()),
+			SearchFormat::Ndjson => serde_json::to_writer(&mut stdout, &message)
+				.map_err(std::io::Error::from)
+				.and_then(|_| stdout.write_all(b"\n")),
+		}
+		.wrap_err("failed to write to stdout")?;
+	}
+	stdout.flush().wrap_err("failed to write to stdout")
+}
@@ -5,23 +5,30 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::{
+	archive,
 	config::Config,
+	emotes,
 	error::{Error, Result},
-	rollup::MAX_MESSAGES_PER_PAGE,
+	justlog,
+	live::{self, LiveEvent},
+	recent_messages,
 };
 use async_stream::try_stream;
 use axum::{
 	body::StreamBody,
-	extract::{Path, State},
+	extract::{FromRef, Path, State},
 	http::StatusCode,
 	response::IntoResponse,
 	routing::get,
-	Router,
+	Json, Router,
 };
 use axum_extra::extract::Query;
-use entity::messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message};
-use futures_util::Stream;
-use sea_orm::{prelude::*, DatabaseConnection, EntityTrait, QueryOrder, QuerySelect};
+use entity::{
+	messages::{Column as MessageColumn, Entity as MessageEntity, Model as Message},
+	streams::Entity as StreamEntity,
+};
+use futures_util::{pin_mut, Stream, StreamExt};
+use sea_orm::{prelude::*, Condition, DatabaseConnection, EntityTrait, QueryOrder, QuerySelect};
 use serde::Deserialize;
 use std::{
 	net::{Ipv4Addr, SocketAddr},
@@ -32,9 +39,34 @@ use time::{
 	macros::format_description,
 	OffsetDateTime, PrimitiveDateTime, UtcOffset,
 };
+use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 
 pub const MAX_MESSAGES_TO_READ: u64 = 1_000_000;
+/// The most messages that can be requested on either side of a message's
+/// context
+pub const MAX_CONTEXT_MESSAGES: u64 = 1_000;
+/// The most emotes that can be requested from the top emotes
+pub const MAX_TOP_EMOTES: u64 = 1_000;
+
+#[derive(Clone)]
+pub struct AppState {
+	pub db: DatabaseConnection,
+	pub config: Arc<Config>,
+	pub live_tx: broadcast::Sender<LiveEvent>,
+}
+
+impl FromRef<AppState> for DatabaseConnection {
+	fn from_ref(state: &AppState) -> Self {
+		state.db.clone()
+	}
+}
+
+impl FromRef<AppState> for Arc<Config> {
+	fn from_ref(state: &AppState) -> Self {
+		state.config.clone()
+	}
+}
 
 #[derive(Deserialize)]
 struct QueryParams {
@@ -53,7 +85,35 @@ struct QueryParams {
 	end_time: Option<String>,
 }
 
-fn format_message(message: &Message) -> String {
+#[derive(Deserialize)]
+struct ContextParams {
+	#[serde(default = "default_context_size")]
+	before: u64,
+	#[serde(default = "default_context_size")]
+	after: u64,
+}
+
+fn default_context_size() -> u64 {
+	50
+}
+
+#[derive(Deserialize)]
+struct TopEmotesParams {
+	#[serde(rename = "start-time", alias = "start", alias = "from")]
+	start_time: Option<String>,
+	#[serde(rename = "end-time", alias = "end", alias = "to")]
+	end_time: Option<String>,
+	/// Only count the emotes used during a stream
+	stream: Option<String>,
+	#[serde(default = "default_top_emotes")]
+	limit: u64,
+}
+
+fn default_top_emotes() -> u64 {
+	50
+}
+
+pub fn format_message(message: &Message) -> String {
 	if let Some(deleted_at) = message.deleted_at {
 		format!(
 			"[{}] <{}; deleted at {}> {}\n",
@@ -84,7 +144,8 @@ fn format_message(message: &Message) -> String {
 	}
 }
 
-fn convert_query_to_datetime(timestamp: Option<&str>) -> Option<PrimitiveDateTime> {
+/// Parses a Unix timestamp, or an RFC 3339 or RFC 2822 time, into UTC
+pub fn convert_query_to_datetime(timestamp: Option<&str>) -> Option<PrimitiveDateTime> {
 	timestamp.and_then(|timestamp| {
 		timestamp
 			.parse::<i64>()
@@ -110,61 +171,181 @@ fn convert_query_to_datetime(timestamp: Option<&str>) -> Option<PrimitiveDateTim
 
 fn response_stream(
 	db: DatabaseConnection,
-	query: Select<MessageEntity>,
+	config: Arc<Config>,
+	channel: String,
+	users: Vec<String>,
+	start_time: Option<PrimitiveDateTime>,
+	end_time: Option<PrimitiveDateTime>,
 ) -> impl Stream<Item = Result<String>> {
 	try_stream! {
-		let mut message_pages = query.paginate(&db, MAX_MESSAGES_PER_PAGE);
-		while let Some(messages) = message_pages.fetch_and_next().await.map_err(Error::from)?{
-			for message in messages {
-				yield format_message(&message);
-			}
+		let messages = archive::search(
+			&db,
+			&config,
+			&channel,
+			&users,
+			start_time,
+			end_time,
+			MAX_MESSAGES_TO_READ,
+		);
+		pin_mut!(messages);
+		while let Some(message) = messages.next().await {
+			let message = message.map_err(|error| Error::Archive(format!("{:#}", error)))?;
+			yield format_message(&message);
 		}
 	}
 }
 
+/// Builds the query for a channel's messages, optionally only from some users
+/// and within a range of time.
+pub fn search_query(
+	channel: &str,
+	users: &[String],
+	start_time: Option<PrimitiveDateTime>,
+	end_time: Option<PrimitiveDateTime>,
+) -> Select<MessageEntity> {
+	let mut query = MessageEntity::find().filter(MessageColumn::Channel.eq(channel.to_lowercase()));
+	if !users.is_empty() {
+		query = query
+			.filter(MessageColumn::Username.is_in(users.iter().map(|user| user.to_lowercase())));
+	}
+	if let Some(start_time) = start_time {
+		query = query.filter(MessageColumn::Timestamp.gte(start_time));
+	}
+	if let Some(end_time) = end_time {
+		query = query.filter(MessageColumn::Timestamp.lte(end_time));
+	}
+	query
+}
+
 async fn search(
 	State(db): State<DatabaseConnection>,
+	State(config): State<Arc<Config>>,
 	Path(channel): Path<String>,
 	Query(params): Query<QueryParams>,
 ) -> Result<impl IntoResponse> {
-	let mut message_pages =
-		MessageEntity::find().filter(MessageColumn::Channel.eq(channel.to_lowercase()));
-	let mut user_query: Option<migration::SimpleExpr> = None;
-	for user in params.users {
-		let user = user.to_lowercase();
-		user_query = match user_query {
-			Some(user_query) => Some(user_query.or(MessageColumn::Username.eq(user))),
-			None => Some(MessageColumn::Username.eq(user)),
-		}
-	}
-	if let Some(user_query) = user_query {
-		message_pages = message_pages.filter(user_query);
-	}
-	if let Some(start_time) = convert_query_to_datetime(params.start_time.as_deref()) {
-		message_pages = message_pages.filter(MessageColumn::Timestamp.gte(start_time));
-	}
-	if let Some(end_time) = convert_query_to_datetime(params.end_time.as_deref()) {
-		message_pages = message_pages.filter(MessageColumn::Timestamp.lte(end_time));
-	}
-
 	Ok((
 		StatusCode::OK,
 		StreamBody::new(response_stream(
 			db,
-			message_pages
-				.order_by_asc(MessageColumn::Timestamp)
-				.limit(MAX_MESSAGES_TO_READ)
-				.order_by_desc(MessageColumn::Timestamp),
+			config,
+			channel,
+			params.users,
+			convert_query_to_datetime(params.start_time.as_deref()),
+			convert_query_to_datetime(params.end_time.as_deref()),
 		)),
 	))
 }
 
+async fn context(
+	State(db): State<DatabaseConnection>,
+	Path(id): Path<Uuid>,
+	Query(params): Query<ContextParams>,
+) -> Result<impl IntoResponse> {
+	let message = MessageEntity::find_by_id(id)
+		.one(&db)
+		.await?
+		.ok_or(Error::MessageNotFound(id))?;
+
+	// Messages sent at the same time as it are ordered by ID, so the window
+	// doesn't depend on how the database orders them
+	let mut before = MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(message.channel.clone()))
+		.filter(
+			Condition::any()
+				.add(MessageColumn::Timestamp.lt(message.timestamp))
+				.add(
+					Condition::all()
+						.add(MessageColumn::Timestamp.eq(message.timestamp))
+						.add(MessageColumn::Id.lt(message.id)),
+				),
+		)
+		.order_by_desc(MessageColumn::Timestamp)
+		.order_by_desc(MessageColumn::Id)
+		.limit(params.before.min(MAX_CONTEXT_MESSAGES))
+		.all(&db)
+		.await?;
+	before.reverse();
+	let after = MessageEntity::find()
+		.filter(MessageColumn::Channel.eq(message.channel.clone()))
+		.filter(
+			Condition::any()
+				.add(MessageColumn::Timestamp.gt(message.timestamp))
+				.add(
+					Condition::all()
+						.add(MessageColumn::Timestamp.eq(message.timestamp))
+						.add(MessageColumn::Id.gt(message.id)),
+				),
+		)
+		.order_by_asc(MessageColumn::Timestamp)
+		.order_by_asc(MessageColumn::Id)
+		.limit(params.after.min(MAX_CONTEXT_MESSAGES))
+		.all(&db)
+		.await?;
+
+	let body = before
+		.iter()
+		.chain(std::iter::once(&message))
+		.chain(after.iter())
+		.map(format_message)
+		.collect::<String>();
+
+	Ok((StatusCode::OK, body))
+}
+
+async fn top_emotes(
+	State(db): State<DatabaseConnection>,
+	Path(channel): Path<String>,
+	Query(params): Query<TopEmotesParams>,
+) -> Result<impl IntoResponse> {
+	let channel = channel.to_lowercase();
+	let mut start_time = convert_query_to_datetime(params.start_time.as_deref());
+	let mut end_time = convert_query_to_datetime(params.end_time.as_deref());
+	if let Some(id) = params.stream {
+		let stream = StreamEntity::find_by_id(id.clone())
+			.one(&db)
+			.await?
+			.filter(|stream| stream.channel == channel)
+			.ok_or(Error::StreamNotFound(id))?;
+		start_time = Some(start_time.map_or(stream.started_at, |start_time| {
+			start_time.max(stream.started_at)
+		}));
+		if let Some(ended_at) = stream.ended_at {
+			end_time = Some(end_time.map_or(ended_at, |end_time| end_time.min(ended_at)));
+		}
+	}
+
+	let emotes = emotes::top_emotes(
+		&db,
+		&channel,
+		start_time,
+		end_time,
+		params.limit.min(MAX_TOP_EMOTES),
+	)
+	.await?;
+	Ok(Json(emotes))
+}
+
 pub async fn run_server(
 	config: Arc<Config>,
 	db: DatabaseConnection,
+	live_tx: broadcast::Sender<LiveEvent>,
 	cancel_token: CancellationToken,
 ) {
-	let app = Router::with_state(db).route("/search/:channel", get(search));
+	let state = AppState {
+		db,
+		config: config.clone(),
+		live_tx,
+	};
+	let app = Router::with_state(state.clone())
+		.route("/search/:channel", get(search))
+		.route("/messages/:id/context", get(context))
+		.route("/live/:channel", get(live::live))
+		.route("/channels/:channel/emotes/top", get(top_emotes))
+		.route(
+			"/api/v2/recent-messages/:channel",
+			get(recent_messages::recent_messages),
+		)
+		.merge(justlog::router(state));
 
 	let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port));
 	info!("listening on {}", addr);
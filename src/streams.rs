@@ -0,0 +1,179 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tracks when channels go live and offline, so channels can have a rollup for
+//! each stream. Twitch's chat doesn't say when a stream starts or ends, so the
+//! Helix API is polled for it.
+
+use crate::{config::Config, rollup};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use entity::streams::{
+	ActiveModel as StreamActiveModel, Column as StreamColumn, Entity as StreamEntity,
+};
+use sea_orm::{prelude::*, ActiveValue, DatabaseConnection};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, PrimitiveDateTime};
+use twitch_oauth2::{AppAccessToken, ClientId, ClientSecret, TwitchToken};
+
+/// How often to check which channels are live
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Helix only takes 100 channels per request
+const MAX_CHANNELS_PER_REQUEST: usize = 100;
+
+#[derive(Deserialize)]
+struct HelixStreams {
+	data: Vec<HelixStream>,
+}
+
+#[derive(Deserialize)]
+struct HelixStream {
+	id: String,
+	user_login: String,
+	started_at: String,
+}
+
+fn now() -> PrimitiveDateTime {
+	let now = OffsetDateTime::now_utc();
+	PrimitiveDateTime::new(now.date(), now.time())
+}
+
+async fn get_app_token(http_client: &reqwest::Client, config: &Config) -> Result<AppAccessToken> {
+	AppAccessToken::get_app_access_token(
+		http_client,
+		ClientId::new(config.twitch.client_id.clone()),
+		ClientSecret::new(config.twitch.client_secret.clone()),
+		vec![],
+	)
+	.await
+	.map_err(|error| eyre!("failed to get app access token: {}", error))
+}
+
+/// Gets the streams that are live out of some channels
+async fn live_streams(
+	http_client: &reqwest::Client,
+	token: &AppAccessToken,
+	channels: &[String],
+) -> Result<Vec<HelixStream>> {
+	let mut streams = Vec::new();
+	for channels in channels.chunks(MAX_CHANNELS_PER_REQUEST) {
+		let query = channels
+			.iter()
+			.map(|channel| ("user_login", channel.as_str()))
+			.collect::<Vec<_>>();
+		let response = http_client
+			.get("https://api.twitch.tv/helix/streams")
+			.query(&query)
+			.header("Client-Id", token.client_id().as_str())
+			.bearer_auth(token.token().secret())
+			.send()
+			.await
+			.wrap_err("failed to request streams")?
+			.error_for_status()
+			.wrap_err("failed to get streams")?
+			.text()
+			.await
+			.wrap_err("failed to read streams")?;
+		streams.extend(
+			serde_json::from_str::<HelixStreams>(&response)
+				.wrap_err("failed to parse streams")?
+				.data,
+		);
+	}
+	Ok(streams)
+}
+
+/// Records streams that have started, and ends and rolls up streams that
+/// aren't live anymore.
+async fn update_streams(
+	db: &DatabaseConnection,
+	config: &Config,
+	live: Vec<HelixStream>,
+) -> Result<()> {
+	let now = now();
+	for stream in &live {
+		if let Some(existing) = StreamEntity::find_by_id(stream.id.clone())
+			.one(db)
+			.await
+			.wrap_err("failed to get stream")?
+		{
+			let mut existing: StreamActiveModel = existing.into();
+			existing.last_seen_at = ActiveValue::Set(Some(now));
+			existing
+				.update(db)
+				.await
+				.wrap_err("failed to update stream")?;
+			continue;
+		}
+		let started_at = OffsetDateTime::parse(&stream.started_at, &Rfc3339)
+			.wrap_err("failed to parse stream start time")?;
+		info!("{} went live", stream.user_login);
+		StreamEntity::insert(StreamActiveModel {
+			id: ActiveValue::Set(stream.id.clone()),
+			channel: ActiveValue::Set(stream.user_login.to_lowercase()),
+			started_at: ActiveValue::Set(PrimitiveDateTime::new(
+				started_at.date(),
+				started_at.time(),
+			)),
+			ended_at: ActiveValue::Set(None),
+			last_seen_at: ActiveValue::Set(Some(now)),
+		})
+		.exec(db)
+		.await
+		.wrap_err("failed to save stream")?;
+	}
+
+	let ended = StreamEntity::find()
+		.filter(StreamColumn::Channel.is_in(config.stream_channels()))
+		.filter(StreamColumn::EndedAt.is_null())
+		.filter(StreamColumn::Id.is_not_in(live.into_iter().map(|stream| stream.id)))
+		.all(db)
+		.await
+		.wrap_err("failed to get streams")?;
+	for stream in ended {
+		info!("{} went offline", stream.channel);
+		// If the logger was down when the stream ended, it ended at most a poll
+		// after it was last seen
+		let ended_at = stream
+			.last_seen_at
+			.map_or(now, |last_seen_at| now.min(last_seen_at + POLL_INTERVAL));
+		let mut stream: StreamActiveModel = stream.into();
+		stream.ended_at = ActiveValue::Set(Some(ended_at));
+		let stream = stream.update(db).await.wrap_err("failed to end stream")?;
+		rollup::rollup_stream(db, config, &stream)
+			.await
+			.wrap_err_with(|| format!("rollup for stream {} failed", stream.id))?;
+	}
+	Ok(())
+}
+
+pub async fn stream_task(db: DatabaseConnection, config: Arc<Config>) {
+	let http_client = reqwest::Client::new();
+	let channels = config.stream_channels();
+	let mut token: Option<AppAccessToken> = None;
+	loop {
+		if token.as_ref().is_none_or(|token| token.is_elapsed()) {
+			token = match get_app_token(&http_client, &config).await {
+				Ok(token) => Some(token),
+				Err(error) => {
+					error!("Getting a token to check for streams failed: {:?}", error);
+					None
+				}
+			};
+		}
+		if let Some(token) = &token {
+			let result = match live_streams(&http_client, token, &channels).await {
+				Ok(live) => update_streams(&db, &config, live).await,
+				Err(error) => Err(error),
+			};
+			if let Err(error) = result {
+				error!("Checking for streams failed: {:?}", error);
+			}
+		}
+		tokio::time::sleep(POLL_INTERVAL).await;
+	}
+}
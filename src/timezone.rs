@@ -0,0 +1,59 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Helpers for the IANA timezones channels can be rolled up in. Timestamps in
+//! the database are always UTC.
+
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use time_tz::{timezones, OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz};
+
+pub fn utc() -> &'static Tz {
+	timezones::get_by_name("UTC").expect("UTC is missing from the timezone database")
+}
+
+/// Gets a timezone by its IANA name, like `America/New_York`
+pub fn by_name(name: &str) -> Option<&'static Tz> {
+	timezones::get_by_name(name)
+}
+
+pub fn same_zone(a: &Tz, b: &Tz) -> bool {
+	a.name() == b.name()
+}
+
+fn to_utc(dt: OffsetDateTime) -> PrimitiveDateTime {
+	let dt = dt.to_offset(UtcOffset::UTC);
+	PrimitiveDateTime::new(dt.date(), dt.time())
+}
+
+/// Converts a UTC timestamp into a timezone's local time
+pub fn to_local(timestamp: PrimitiveDateTime, zone: &Tz) -> PrimitiveDateTime {
+	let local = timestamp.assume_utc().to_timezone(zone);
+	PrimitiveDateTime::new(local.date(), local.time())
+}
+
+/// Converts a timezone's local time into UTC. Times repeated by daylight
+/// saving are taken as the first of them, and times skipped by it as the hour
+/// after.
+pub fn from_local(local: PrimitiveDateTime, zone: &Tz) -> PrimitiveDateTime {
+	match local.assume_timezone(zone) {
+		OffsetResult::Some(dt) | OffsetResult::Ambiguous(dt, _) => to_utc(dt),
+		OffsetResult::None => to_utc(
+			(local + Duration::HOUR)
+				.assume_timezone(zone)
+				.unwrap_first(),
+		),
+	}
+}
+
+/// Gets when a local day starts, in UTC
+pub fn start_of_day(date: Date, zone: &Tz) -> PrimitiveDateTime {
+	from_local(date.with_time(Time::MIDNIGHT), zone)
+}
+
+/// Gets the current local date in a timezone
+pub fn today(zone: &Tz) -> Date {
+	OffsetDateTime::now_utc().to_timezone(zone).date()
+}
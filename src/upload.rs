@@ -0,0 +1,350 @@
+// Copyright 2022  Lucy <lucy@absolucy.moe>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Uploads finished rollups to S3-compatible object storage, like AWS S3 or
+//! MinIO. Requests are signed with AWS Signature Version 4.
+
+use crate::{
+	config::{Config, UploadConfig},
+	manifest::{Manifest, ManifestEntry},
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use hmac::{Hmac, Mac};
+use reqwest::{Body, Client, Url};
+use sha2::{Digest, Sha256};
+use std::{path::Path, time::Duration};
+use time::{macros::format_description, OffsetDateTime};
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
+
+/// How long to wait before retrying a failed upload, doubling each time
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+static UPLOAD_LOCK: Mutex<()> = Mutex::const_new(());
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+	mac.update(data.as_bytes());
+	mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes a key the way S3 expects in a path, leaving its slashes
+fn encode_key(key: &str) -> String {
+	key.bytes()
+		.map(|byte| match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+				(byte as char).to_string()
+			}
+			_ => format!("%{:02X}", byte),
+		})
+		.collect()
+}
+
+/// The names of the headers that are signed, which are lowercase and sorted
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+	headers
+		.iter()
+		.map(|(name, _)| *name)
+		.collect::<Vec<_>>()
+		.join(";")
+}
+
+/// Makes the canonical request of a request without a query string, with its
+/// headers lowercase and sorted by name
+fn canonical_request(
+	method: &str,
+	uri: &str,
+	headers: &[(&str, &str)],
+	payload_sha256: &str,
+) -> String {
+	format!(
+		"{}\n{}\n\n{}\n{}\n{}",
+		method,
+		uri,
+		headers
+			.iter()
+			.map(|(name, value)| format!("{}:{}\n", name, value))
+			.collect::<String>(),
+		signed_headers(headers),
+		payload_sha256
+	)
+}
+
+fn string_to_sign(timestamp: &str, scope: &str, canonical_request: &str) -> String {
+	format!(
+		"AWS4-HMAC-SHA256\n{}\n{}\n{}",
+		timestamp,
+		scope,
+		hex::encode(Sha256::digest(canonical_request.as_bytes()))
+	)
+}
+
+fn signature(secret_key: &str, date: &str, region: &str, string_to_sign: &str) -> String {
+	let date_key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+	let region_key = hmac(&date_key, region);
+	let service_key = hmac(&region_key, "s3");
+	let signing_key = hmac(&service_key, "aws4_request");
+	hex::encode(hmac(&signing_key, string_to_sign))
+}
+
+/// Uploads a rollup, with its SHA-256 hash for the storage to check it against
+async fn put_object(
+	client: &Client,
+	upload: &UploadConfig,
+	key: &str,
+	path: &Path,
+	entry: &ManifestEntry,
+) -> Result<()> {
+	let mut url = Url::parse(&upload.endpoint).wrap_err("failed to parse upload endpoint")?;
+	let host = match (url.host_str(), url.port()) {
+		(Some(host), Some(port)) => format!("{}:{}", host, port),
+		(Some(host), None) => host.to_string(),
+		(None, _) => return Err(eyre!("upload endpoint has no host")),
+	};
+	let uri = format!(
+		"{}/{}/{}",
+		url.path().trim_end_matches('/'),
+		upload.bucket,
+		encode_key(key)
+	);
+	url.set_path(&uri);
+
+	let now = OffsetDateTime::now_utc();
+	let date = now.format(format_description!("[year][month][day]"))?;
+	let timestamp = now.format(format_description!(
+		"[year][month][day]T[hour][minute][second]Z"
+	))?;
+	let checksum =
+		base64::encode(hex::decode(&entry.sha256).wrap_err("manifest has an invalid hash")?);
+	let headers = [
+		("host", host.as_str()),
+		("x-amz-checksum-sha256", checksum.as_str()),
+		("x-amz-content-sha256", entry.sha256.as_str()),
+		("x-amz-date", timestamp.as_str()),
+	];
+	let scope = format!("{}/{}/s3/aws4_request", date, upload.region);
+	let string_to_sign = string_to_sign(
+		&timestamp,
+		&scope,
+		&canonical_request("PUT", &uri, &headers, &entry.sha256),
+	);
+	let signature = signature(&upload.secret_key, &date, &upload.region, &string_to_sign);
+
+	let file = tokio::fs::File::open(path)
+		.await
+		.wrap_err_with(|| format!("failed to open {}", path.display()))?;
+	let response = client
+		.put(url)
+		.header("x-amz-date", &timestamp)
+		.header("x-amz-content-sha256", &entry.sha256)
+		.header("x-amz-checksum-sha256", &checksum)
+		.header(
+			"authorization",
+			format!(
+				"AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+				upload.access_key,
+				scope,
+				signed_headers(&headers),
+				signature
+			),
+		)
+		.header("content-length", entry.bytes)
+		.body(Body::wrap_stream(ReaderStream::new(file)))
+		.send()
+		.await
+		.wrap_err("failed to send upload")?;
+	if !response.status().is_success() {
+		let status = response.status();
+		let body = response.text().await.unwrap_or_default();
+		return Err(eyre!("upload failed with {}: {}", status, body));
+	}
+	Ok(())
+}
+
+/// Uploads a rollup, trying again after a while if it fails
+async fn upload_with_retries(
+	client: &Client,
+	upload: &UploadConfig,
+	key: &str,
+	path: &Path,
+	entry: &ManifestEntry,
+) -> Result<()> {
+	let mut delay = RETRY_DELAY;
+	let mut attempt = 1;
+	loop {
+		match put_object(client, upload, key, path, entry).await {
+			Ok(()) => return Ok(()),
+			Err(error) if attempt < upload.attempts => {
+				warn!(
+					"Uploading '{}' failed, retrying in {} seconds: {:?}",
+					key,
+					delay.as_secs(),
+					error
+				);
+				tokio::time::sleep(delay).await;
+				delay *= 2;
+				attempt += 1;
+			}
+			Err(error) => return Err(error),
+		}
+	}
+}
+
+/// Uploads every rollup in the manifest that hasn't been uploaded yet, marking
+/// each one as uploaded in the manifest as soon as it is. If one keeps failing,
+/// the rest are left for the next time, as the storage is probably down.
+pub async fn upload_rollups(config: &Config) -> Result<()> {
+	let upload = match &config.upload {
+		Some(upload) => upload,
+		None => return Ok(()),
+	};
+	// Only one upload runs at a time, so a rollup isn't uploaded twice, or
+	// deleted while it's being uploaded
+	let _lock = UPLOAD_LOCK.lock().await;
+	let client = Client::new();
+	let manifest = Manifest::read(config).await?;
+	for (path, entry) in &manifest.rollups {
+		let full_path = config.rollup_dir.join(path);
+		if entry.uploaded || !full_path.exists() {
+			continue;
+		}
+		let key = format!("{}{}", upload.prefix, path);
+		upload_with_retries(&client, upload, &key, &full_path, entry)
+			.await
+			.wrap_err_with(|| format!("failed to upload '{}'", path))?;
+		Manifest::update(config, |manifest| {
+			if let Some(entry) = manifest.rollups.get_mut(path) {
+				entry.uploaded = true;
+			}
+		})
+		.await?;
+		info!("Uploaded '{}'", key);
+		if upload.delete_local {
+			tokio::fs::remove_file(&full_path)
+				.await
+				.wrap_err_with(|| format!("failed to delete {}", full_path.display()))?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::manifest::temp_path;
+
+	async fn write_rollup(config: &Config, name: &str) -> Result<()> {
+		let path = config.rollup_dir.join(name);
+		tokio::fs::write(temp_path(&path), format!("{}\n", name)).await?;
+		Manifest::finish_rollup(config, &path, 1).await
+	}
+
+	/// The "PUT Object" example of AWS's Signature Version 4 documentation
+	#[test]
+	fn signs_aws_example() {
+		let payload_sha256 = "44ce7dd67c959e0d3524ffac1771dfbba87d2b6b4b4e99e42034a8b803f8b072";
+		let headers = [
+			("date", "Fri, 24 May 2013 00:00:00 GMT"),
+			("host", "examplebucket.s3.amazonaws.com"),
+			("x-amz-content-sha256", payload_sha256),
+			("x-amz-date", "20130524T000000Z"),
+			("x-amz-storage-class", "REDUCED_REDUNDANCY"),
+		];
+		let canonical_request = canonical_request(
+			"PUT",
+			&format!("/{}", encode_key("test$file.text")),
+			&headers,
+			payload_sha256,
+		);
+		assert_eq!(
+			canonical_request,
+			concat!(
+				"PUT\n/test%24file.text\n\n",
+				"date:Fri, 24 May 2013 00:00:00 GMT\n",
+				"host:examplebucket.s3.amazonaws.com\n",
+				"x-amz-content-sha256:\
+				 44ce7dd67c959e0d3524ffac1771dfbba87d2b6b4b4e99e42034a8b803f8b072\n",
+				"x-amz-date:20130524T000000Z\n",
+				"x-amz-storage-class:REDUCED_REDUNDANCY\n\n",
+				"date;host;x-amz-content-sha256;x-amz-date;x-amz-storage-class\n",
+				"44ce7dd67c959e0d3524ffac1771dfbba87d2b6b4b4e99e42034a8b803f8b072"
+			)
+		);
+		let string_to_sign = string_to_sign(
+			"20130524T000000Z",
+			"20130524/us-east-1/s3/aws4_request",
+			&canonical_request,
+		);
+		assert_eq!(
+			string_to_sign,
+			concat!(
+				"AWS4-HMAC-SHA256\n20130524T000000Z\n20130524/us-east-1/s3/aws4_request\n",
+				"9e0e90d9c76de8fa5b200d8c849cd5b8dc7a3be3951ddb7f6a76b4158342019d"
+			)
+		);
+		assert_eq!(
+			signature(
+				"wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+				"20130524",
+				"us-east-1",
+				&string_to_sign
+			),
+			"98ad721746da40c64f1a55b78f14c238d841ea1380cd77a1b5971af0ece108bd"
+		);
+	}
+
+	/// Uploads to a real bucket, like one on a local MinIO, given by
+	/// `TEST_S3_ENDPOINT`, `TEST_S3_BUCKET`, `TEST_S3_ACCESS_KEY` and
+	/// `TEST_S3_SECRET_KEY`.
+	#[tokio::test]
+	#[ignore = "needs an S3 bucket"]
+	async fn upload_keeps_concurrent_rollups() -> Result<()> {
+		let var = |name: &str| std::env::var(name).wrap_err_with(|| format!("{} isn't set", name));
+		let endpoint = var("TEST_S3_ENDPOINT")?;
+		let rollup_dir = std::env::temp_dir().join(format!("upload-test-{}", std::process::id()));
+		tokio::fs::create_dir_all(&rollup_dir).await?;
+		let config = ron::from_str::<Config>(&format!(
+			r#"(
+				twitch: (
+					username: "", access_token: "", refresh_token: "", client_id: "",
+					client_secret: "", channels: [],
+				),
+				database: "",
+				rollup_dir: {:?},
+				upload: Some((
+					endpoint: {:?}, bucket: {:?}, prefix: "upload-test/", access_key: {:?},
+					secret_key: {:?}, attempts: 1, delete_local: true,
+				)),
+				port: 0,
+			)"#,
+			rollup_dir,
+			endpoint,
+			var("TEST_S3_BUCKET")?,
+			var("TEST_S3_ACCESS_KEY")?,
+			var("TEST_S3_SECRET_KEY")?,
+		))?;
+		let uploaded = ["jerma985_2022-09-07.log", "jerma985_2022-09-08.log"];
+		for name in uploaded {
+			write_rollup(&config, name).await?;
+		}
+
+		let (upload, rollup) = tokio::join!(
+			upload_rollups(&config),
+			write_rollup(&config, "jerma985_2022-09-09.log")
+		);
+		upload?;
+		rollup?;
+
+		let manifest = Manifest::read(&config).await?;
+		assert_eq!(manifest.rollups.len(), 3);
+		for name in uploaded {
+			assert!(manifest.rollups[name].uploaded);
+			assert!(!config.rollup_dir.join(name).exists());
+		}
+		tokio::fs::remove_dir_all(&config.rollup_dir).await?;
+		Ok(())
+	}
+}